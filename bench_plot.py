@@ -5,13 +5,18 @@ import sys
 
 def main():
     df = pd.read_csv('bench_rluset.csv')
+    # results recorded before the mode and wait strategy columns existed
+    if 'Mode' not in df:
+        df['Mode'] = 'Eager'
+    if 'Wait' not in df:
+        df['Wait'] = 'Spin'
     
-    # Group by write ratio
-    grouped = df.groupby('Write_Ratio')
+    # Group by mode, wait strategy and write ratio
+    grouped = df.groupby(['Mode', 'Wait', 'Write_Ratio'])
     
     # Plot for each group
-    for name, group in grouped:
-        plt.plot(group['Thread_Count'], group['Throughput'], label=f'Write Ratio {name}')
+    for (mode, wait, ratio), group in grouped:
+        plt.plot(group['Thread_Count'], group['Throughput'], label=f'{mode}/{wait}, Write Ratio {ratio}')
 
     # Add legend
     plt.legend()
@@ -5,15 +5,17 @@ extern crate rand;
 use std::{thread, time::Instant};
 
 use rand::{rngs::SmallRng, Rng, SeedableRng};
+use std::sync::Arc;
 use rlu::{
   rlu_dereference, rlu_reader_lock, rlu_reader_unlock,
-  rlu_try_lock, rlu_thread_init, rlu_abort, RluGlobal, Rlu
+  rlu_thread_init, RluConfig, RluGlobal, Rlu,
+  BoundedRetries, ContentionPolicy, ExponentialBackoff, ImmediateRetry, YieldRetry
 };
 
 #[derive(Copy, Clone, Debug)]
 pub struct RluInt64Wrapper {
   pub obj : *mut Rlu<u64>,
-  pub rlu_global : *mut RluGlobal<u64>
+  pub rlu_global : *mut RluGlobal
 }
 
 unsafe impl Send for RluInt64Wrapper {}
@@ -63,32 +65,21 @@ fn read_write(rw : RluInt64Wrapper, config : BenchmarkConfig) -> BenchmarkResult
                     //println!("write op: {}, thread {}", ops, n_threads);
                     let curr = Instant::now();
 
-                    // write operation
-                    'inner: loop {
-                        rlu_reader_lock(g, id);
-                        let locked_obj = rlu_try_lock(g, id, obj);
-
-                        match locked_obj {
-                            None => {
-                              rlu_abort(g, id);
-                              continue;
-                            }
-                
-                            Some(locked_obj) => {
-                              *locked_obj += 1;
-                              break 'inner;
-                            }
-                          }
-                    } 
-                    rlu_reader_unlock(g, id);
-                    results.writes += 1;
-                    results.write_times += curr.elapsed().as_nanos();
+                    // write operation, a bounded policy may give up on it
+                    let written = (*g).write((*g).token(id), |tx| {
+                        *tx.lock(&*obj)? += 1;
+                        Ok(())
+                    });
+                    if written.is_ok() {
+                        results.writes += 1;
+                        results.write_times += curr.elapsed().as_nanos();
+                    }
                 } else {
                     // read operation
                     let curr = Instant::now();
-                    rlu_reader_lock(g, id);
-                    let read_obj = rlu_dereference(g, id, obj);
-                    rlu_reader_unlock(g, id);
+                    rlu_reader_lock(g, id).unwrap();
+                    let read_obj = rlu_dereference(g, id, obj).unwrap();
+                    rlu_reader_unlock(g, id).unwrap();
 
 
                     results.reads += 1;
@@ -129,32 +120,49 @@ fn benchmark() {
 
     
 
-    println!("Write_Ratio,Thread_Count,Throughput");
-    for wr in &[0.02, 0.2, 0.4] {
-        for i in 1..=8 {
-            let config = BenchmarkConfig {
-                write_ratio: *wr,
-                n_threads: i,
-                timeout: 10000,
-            };
-
-
-            let rlu_global : *mut RluGlobal<u64> = RluGlobal::init();
-            let rlu_global_obj = unsafe { & *rlu_global };
-
-            let int_object = RluInt64Wrapper {
-                obj : Box::into_raw(Box::new(rlu_global_obj.alloc(0))),
-                rlu_global: rlu_global,
-            };
+    let policies: Vec<(&str, Arc<dyn ContentionPolicy>)> = vec![
+        ("Immediate", Arc::new(ImmediateRetry)),
+        ("Backoff", Arc::new(ExponentialBackoff::default())),
+        ("Yield", Arc::new(YieldRetry)),
+        ("Bounded", Arc::new(BoundedRetries { max_retries: 8, policy: YieldRetry })),
+    ];
+
+    println!("Policy,Write_Ratio,Thread_Count,Throughput,Retries,Failed_Writes");
+    for (name, policy) in &policies {
+        for wr in &[0.02, 0.2, 0.4] {
+            for i in 1..=8 {
+                let config = BenchmarkConfig {
+                    write_ratio: *wr,
+                    n_threads: i,
+                    timeout: 10000,
+                };
+
+
+                let rlu_global : *mut RluGlobal = RluGlobal::init_with_config(RluConfig {
+                    contention: policy.clone(),
+                    ..RluConfig::default()
+                });
+                let rlu_global_obj = unsafe { & *rlu_global };
+
+                let int_object = RluInt64Wrapper {
+                    obj : Box::into_raw(Box::new(rlu_global_obj.alloc(0))),
+                    rlu_global,
+                };
+
+                let ops: Vec<_> = (0..3).map(|_| {
+                    read_write(int_object, config)
+                }).collect();
+                
+                let avg: f64 = (ops.iter().map(|res| res.reads).sum::<usize>() as f64)/ (ops.len() as f64);
+                let throughput = avg / ((config.timeout * 1000) as f64);
 
-            let ops: Vec<_> = (0..3).map(|_| {
-                read_write(int_object, config)
-            }).collect();
-            
-            let avg: f64 = (ops.iter().map(|res| res.reads).sum::<usize>() as f64)/ (ops.len() as f64);
-            let throughput = avg / ((config.timeout * 1000) as f64);
+                // the counters cover all three runs
+                let stats = rlu_global_obj.stats();
+                println!("{},{},{},{},{},{}", name, wr, i, throughput,
+                    stats.retries / ops.len(), stats.failed_writes / ops.len());
 
-            println!("{},{},{}", wr, i, throughput);
+                unsafe { RluGlobal::destroy(rlu_global) };
+            }
         }
     }
 }
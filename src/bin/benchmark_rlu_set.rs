@@ -2,8 +2,8 @@
 
 extern crate rand;
 
-use std::{thread, time::Instant};
-use rlu::{RluSet, ConcurrentSet};
+use std::{env, thread, time::Instant};
+use rlu::{RluSet, ConcurrentSet, RluConfig, RluMode, RluWaitStrategy};
 
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 
@@ -30,14 +30,14 @@ struct BenchmarkConfig {
 }
 
 fn read_write(set: RluSet<usize>, config : BenchmarkConfig) -> BenchmarkResult {
-    let worker = || {
+    let worker = |t: u8| {
         let mut results: BenchmarkResult = BenchmarkResult::default();
         let set = set.clone_ref();
 
         thread::spawn(move || {
             let start = Instant::now();
-            // initialize thread
-            let mut ops = 0;
+            // one generator per thread, seeded differently so threads don't replay the same ops
+            let mut _rnd = SmallRng::from_seed([t + 1; 16]);
             loop {
                 if start.elapsed().as_millis() > config.timeout {
                     break;
@@ -45,7 +45,6 @@ fn read_write(set: RluSet<usize>, config : BenchmarkConfig) -> BenchmarkResult {
 
                 let i = Instant::now();
 
-                let mut _rnd = SmallRng::from_seed([0;16]);
                 let num = _rnd.gen_range(0, config.range);
                 if _rnd.gen::<f64>() < config.write_ratio {
                     //println!("write op: {}, thread {}", ops, n_threads);
@@ -64,7 +63,6 @@ fn read_write(set: RluSet<usize>, config : BenchmarkConfig) -> BenchmarkResult {
 
                 results.ops += 1;
                 results.op_times += i.elapsed().as_nanos();
-                ops += 1;
             }
 
             results.n_threads = config.n_threads;
@@ -74,7 +72,7 @@ fn read_write(set: RluSet<usize>, config : BenchmarkConfig) -> BenchmarkResult {
     };
 
 
-    let threads: Vec<_> = (0..config.n_threads).map(|_| worker()).collect();
+    let threads: Vec<_> = (0..config.n_threads).map(worker).collect();
     threads.into_iter().map(|t| t.join().unwrap()).fold(
         BenchmarkResult::default(), 
     
@@ -94,32 +92,57 @@ fn read_write(set: RluSet<usize>, config : BenchmarkConfig) -> BenchmarkResult {
 }
 
 fn benchmark() {
-    println!("Write_Ratio,Thread_Count,Throughput");
-    for wr in &[0.02, 0.2, 0.4] {
-        for i in 1..=8 {
-            let config = BenchmarkConfig {
-                write_ratio: *wr,
-                insert_ratio: 0.5,
-                n_threads: i,
-                timeout: 10000,
-                initial_size: 256,
-                range: 512,
-            };
-
-            let ops: Vec<_> = (0..3).map(|_| {
-                let set = RluSet::new();
-                let mut _rnd = SmallRng::from_seed([0; 16]);
-                while set.len() < config.initial_size {
-                    let i = _rnd.gen_range(0, config.range);
-                    set.insert(i);
-                }
-                read_write(set, config)
-            }).collect();
-            
-            let avg: f64 = (ops.iter().map(|res| res.ops).sum::<usize>() as f64)/ (ops.len() as f64);
-            let throughput = avg / ((config.timeout * 1000) as f64);
+    // The default sweep takes about 12 minutes. RLU_BENCH_FULL=1 adds spinning
+    // writers and deferred mode, and goes up to twice the number of cores, so
+    // writers regularly have to wait for readers that are not running: that takes
+    // an hour or more.
+    let full = env::var_os("RLU_BENCH_FULL").is_some_and(|full| full != "0");
+    let (max_threads, waits, modes) = if full {
+        let cores = thread::available_parallelism().map_or(4, |n| n.get());
+        (
+            (2 * cores).clamp(8, 254) as u8,
+            vec![("Spin", RluWaitStrategy::spin()), ("Park", RluWaitStrategy::default())],
+            vec![RluMode::Eager, RluMode::Deferred],
+        )
+    } else {
+        (8, vec![("Park", RluWaitStrategy::default())], vec![RluMode::Eager])
+    };
+
+    println!("Mode,Wait,Write_Ratio,Thread_Count,Throughput,Retries");
+    for (wait_name, wait) in &waits {
+        for mode in &modes {
+            for wr in &[0.02, 0.2, 0.4] {
+                for i in 1..=max_threads {
+                    let config = BenchmarkConfig {
+                        write_ratio: *wr,
+                        insert_ratio: 0.5,
+                        n_threads: i,
+                        timeout: 10000,
+                        initial_size: 256,
+                        range: 512,
+                    };
+
+                    let mut retries = 0;
+                    let ops: Vec<_> = (0..3).map(|_| {
+                        let set = RluSet::with_config(RluConfig { mode: *mode, wait: *wait, ..RluConfig::default() });
+                        let mut _rnd = SmallRng::from_seed([0; 16]);
+                        while set.len() < config.initial_size {
+                            let i = _rnd.gen_range(0, config.range);
+                            set.insert(i);
+                        }
+                        // in deferred mode the last inserts are still private to this handle
+                        set.sync();
+                        let res = read_write(set.clone_ref(), config);
+                        retries += set.stats().retries;
+                        res
+                    }).collect();
+                    
+                    let avg: f64 = (ops.iter().map(|res| res.ops).sum::<usize>() as f64)/ (ops.len() as f64);
+                    let throughput = avg / ((config.timeout * 1000) as f64);
 
-            println!("{},{},{}", wr, i, throughput);
+                    println!("{:?},{},{},{},{},{}", mode, wait_name, wr, i, throughput, retries / ops.len());
+                }
+            }
         }
     }
 }
@@ -13,6 +13,15 @@ where
     }
 }
 
+impl<T> Default for ConcurrentBTreeSet<T>
+where
+    T: Ord + Send + Sync,
+{
+    fn default() -> ConcurrentBTreeSet<T> {
+        ConcurrentBTreeSet::new()
+    }
+}
+
 impl<T> ConcurrentSet<T> for ConcurrentBTreeSet<T>
 where
     T: Ord + Send + Sync,
@@ -0,0 +1,73 @@
+use std::fmt::Debug;
+use std::hint;
+use std::thread;
+
+/// Decides what a write section does after a `lock` failed and the section was
+/// aborted. Set per RluGlobal through `RluConfig::contention`.
+pub trait ContentionPolicy: Debug + Send + Sync {
+    /// Called with the number of failed attempts so far (starting at 1), before the
+    /// section is retried. Returns false to give up instead of retrying.
+    fn on_conflict(&self, attempts: usize) -> bool;
+}
+
+/// Retry right away. Fine while conflicts are rare, livelocks on hot objects.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImmediateRetry;
+
+impl ContentionPolicy for ImmediateRetry {
+    fn on_conflict(&self, _attempts: usize) -> bool {
+        true
+    }
+}
+
+/// Spin for `min_spins`, doubling on every further conflict up to `max_spins`.
+#[derive(Clone, Copy, Debug)]
+pub struct ExponentialBackoff {
+    pub min_spins: usize,
+    pub max_spins: usize,
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> ExponentialBackoff {
+        ExponentialBackoff {
+            min_spins: 16,
+            max_spins: 16 * 1024,
+        }
+    }
+}
+
+impl ContentionPolicy for ExponentialBackoff {
+    fn on_conflict(&self, attempts: usize) -> bool {
+        let shift = u32::try_from(attempts - 1).unwrap_or(u32::MAX);
+        let factor = 1usize.checked_shl(shift).unwrap_or(usize::MAX);
+        let spins = self.min_spins.saturating_mul(factor).min(self.max_spins);
+        for _ in 0..spins {
+            hint::spin_loop();
+        }
+        true
+    }
+}
+
+/// Give the lock holder our time slice before retrying.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct YieldRetry;
+
+impl ContentionPolicy for YieldRetry {
+    fn on_conflict(&self, _attempts: usize) -> bool {
+        thread::yield_now();
+        true
+    }
+}
+
+/// Follow `policy` for at most `max_retries` retries, then give up.
+#[derive(Clone, Copy, Debug)]
+pub struct BoundedRetries<P> {
+    pub max_retries: usize,
+    pub policy: P,
+}
+
+impl<P : ContentionPolicy> ContentionPolicy for BoundedRetries<P> {
+    fn on_conflict(&self, attempts: usize) -> bool {
+        attempts <= self.max_retries && self.policy.on_conflict(attempts)
+    }
+}
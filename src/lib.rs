@@ -2,8 +2,11 @@ mod rlu;
 mod concurrent_set;
 mod btree_set;
 mod rlu_set;
+mod contention;
+mod sync;
 
 pub use crate::concurrent_set::*;
 pub use crate::btree_set::*;
 pub use crate::rlu_set::*;
-pub use crate::rlu::*;
\ No newline at end of file
+pub use crate::rlu::*;
+pub use crate::contention::*;
\ No newline at end of file
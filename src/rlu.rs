@@ -1,339 +1,1818 @@
 #![allow(dead_code, unused_variables)]
 
-use std::fmt::Debug;
-use std::mem::MaybeUninit;
+use std::alloc::{self, Layout};
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::marker::PhantomData;
+use crate::contention::{ContentionPolicy, ImmediateRetry};
+use std::mem::{self, MaybeUninit};
+use std::ops::{Deref, Index};
 use std::ptr;
 use std::ptr::null_mut;
-use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
-use std::usize;
+use std::sync::mpsc::{self, Sender};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Weak};
+use crate::sync::{fence, spin_loop, yield_now, AtomicBool, AtomicPtr, AtomicUsize, Condvar, Mutex, Ordering};
+use std::thread::{self, JoinHandle};
 
 // Constants
-const RLU_MAX_LOG_SIZE: usize = 128;
-const RLU_MAX_THREADS: usize = 32;
-const RLU_MAX_FREE_NODES: usize = 100;
+// The write log grows by chunks of this many copies.
+const RLU_LOG_CHUNK_SIZE: usize = 128;
+// Thread records live in chunks that double in size, starting at RLU_FIRST_CHUNK_SIZE.
+const RLU_FIRST_CHUNK_SIZE: usize = 32;
+const RLU_MAX_CHUNKS: usize = 26;
+// Default number of pending frees after which a section end forces a commit.
+const RLU_FREE_HIGH_WATER: usize = 100;
+// Default number of copies a deferred writer collects before writing them back.
+const RLU_DEFERRED_LOG_LIMIT: usize = 512;
+// Default number of spins, then yields, before a synchronizing writer parks.
+const RLU_WAIT_SPINS: usize = 128;
+const RLU_WAIT_YIELDS: usize = 16;
+
+// Memory orderings. What the protocol needs from the atomics, and what provides it
+// (the numbers are referred to where the atomics are used):
+//
+// (1) A committing writer either waits for a reader or the reader sees its copies.
+//     The reader increments run_cnt, then loads global_clock; the writer increments
+//     global_clock, then loads every run_cnt. The SeqCst fences in rlu_reader_lock
+//     and readers_in_section rule out both loads missing the other side's store.
+//     The same covers `active` and the registry length for threads that just
+//     registered.
+// (2) A reader whose clock covers a commit sees that commit's copies and its
+//     write_clock: both are written before the Release compare_exchange that
+//     increments global_clock, which the reader loads with Acquire. A reader that
+//     steals a copy also loads write_clock with Acquire, which is stored with Release.
+// (3) A writer that waited for a reader comes after everything the reader did in its
+//     section. Readers leave with a Release increment of run_cnt (or a Release store
+//     of false into `active` on exit), writers load both with Acquire. A writer may
+//     also let a reader go because of the local_clock of the reader's next section,
+//     so local_clock is stored with Release and loaded with Acquire there.
+// (4) Whoever finds an object unlocked sees what was written back to it. Writeback
+//     comes before the Release store of null into ObjOriginal::copy and before the
+//     Release store of usize::MAX into write_clock; both are loaded with Acquire.
+// (5) The header of a copy is filled in before the compare_exchange that publishes
+//     it in ObjOriginal::copy, which is Release, and loaded with Acquire. The data
+//     is cloned only after the compare_exchange, which also acquires (4).
+// (6) Wakeups are not lost. The reader increments run_cnt, then loads waiters; a
+//     parking writer increments waiters, then loads run_cnt. SeqCst fences between
+//     the two on both sides, like (1).
+// (7) Thread records are published with Release stores to the registry and read
+//     with Acquire loads.
+// (8) Whoever takes a thread's owner state sees what the last holder did to it:
+//     `claimed` is taken with an Acquire compare_exchange and given back with a
+//     Release store (see RluThreadData::claim).
+// Everything else (a thread's own local_clock, sync_request, the copy header, the
+// counters in RluStats) is only compared against values ordered by the above, or is
+// a hint, and is Relaxed.
+
+
+// Tracing of every rlu_* call, enabled with the `debug-log` feature. It is far too
+// chatty to leave on for the multi-threaded tests.
+macro_rules! debug_log {
+    ($($rest:tt)*) => {
+        #[cfg(feature = "debug-log")]
+        std::println!($($rest)*);
+    }
+}
+
+
+#[derive(Debug)]
+pub struct ObjOriginal<T> {
+    copy : AtomicPtr<ObjCopy<T>>,
+    domain : u64, // id of the RluGlobal that allocated it, whose locks guard it
+    body : ObjData<T, T>,
+}
+
+// How originals and copies both end: the data, right behind the original it belongs
+// to (which points at itself), so a reference handed out by a section can be traced
+// back to its object whichever version it is (see rlu_same_object). `D` is
+// `MaybeUninit<T>` in copies, which has the layout of `T`.
+#[derive(Debug)]
+#[repr(C)]
+struct ObjData<T, D> {
+    original : *mut ObjOriginal<T>,
+    data : D,
+}
+
+
+// A slot of a write log. The header is atomic because other threads read it through
+// ObjOriginal::copy while the owner may already be refilling the slot (after an
+// abort unlocked it). `data` is only initialized while the slot holds a copy. The
+// header comes first whatever `T` is, so slots can be reused by copies of other
+// types of the same layout (see WriteLog).
+#[derive(Debug)]
+#[repr(C)]
+pub struct ObjCopy<T> {
+    thread_id : AtomicUsize,
+    run_cnt : AtomicUsize, // section of thread_id that locked the object
+    body : ObjData<T, MaybeUninit<T>>,
+}
+
+impl<T> ObjCopy<T> {
+    fn empty() -> ObjCopy<T> {
+        ObjCopy {
+            thread_id: AtomicUsize::new(usize::MAX),
+            run_cnt: AtomicUsize::new(0),
+            body: ObjData {
+                original: null_mut(),
+                data: MaybeUninit::uninit(),
+            },
+        }
+    }
+}
+
+impl<T : ClonedT> ObjCopy<T> {
+    const VTABLE: CopyVTable = CopyVTable {
+        clone: Self::clone_original,
+        writeback: Self::writeback,
+        drop: Self::drop_data,
+        unlock: Self::unlock,
+    };
+
+    unsafe fn clone_original(copy : *mut u8) {
+        let copy = copy.cast::<ObjCopy<T>>();
+        let original = *ptr::addr_of!((*copy).body.original);
+        ptr::addr_of_mut!((*copy).body.data).write(MaybeUninit::new((*original).body.data.clone()));
+    }
+
+    unsafe fn writeback(copy : *mut u8) {
+        let copy = copy.cast::<ObjCopy<T>>();
+        // Move the data over. Readers that stole the copy may keep reading its
+        // bits until they leave their section; the value now owned by the
+        // original is only dropped after a later grace period, so that is
+        // fine. The log forgets the copies once they are unlocked.
+        (*(*copy).body.original).body.data = ptr::read(ptr::addr_of!((*copy).body.data).cast::<T>());
+    }
+
+    unsafe fn drop_data(copy : *mut u8) {
+        let copy = copy.cast::<ObjCopy<T>>();
+        ptr::drop_in_place(ptr::addr_of_mut!((*copy).body.data).cast::<T>());
+    }
+
+    unsafe fn unlock(copy : *mut u8) {
+        let copy = copy.cast::<ObjCopy<T>>();
+        (*(*copy).body.original).copy.store(null_mut(), Ordering::Release); // (4)
+    }
+}
+
+// What a write log does with a copy without knowing its type. Each takes a pointer
+// to an ObjCopy of that type.
+struct CopyVTable {
+    clone : unsafe fn(*mut u8), // fills the data in from the original
+    writeback : unsafe fn(*mut u8), // moves the data into the original
+    drop : unsafe fn(*mut u8), // drops the data
+    unlock : unsafe fn(*mut u8), // clears ObjOriginal::copy
+}
+
+// An object of any type, waiting to be dropped once nothing can reach it anymore.
+struct ErasedObj {
+    original : *mut u8, // an ObjOriginal of the type `drop` was made for
+    drop : unsafe fn(*mut u8),
+}
+
+// Objects are ClonedT, which is Send.
+unsafe impl Send for ErasedObj {}
+
+impl ErasedObj {
+    fn new<T : ClonedT>(obj : Rlu<T>) -> ErasedObj {
+        ErasedObj {
+            original: obj.0.cast(),
+            drop: Self::drop_original::<T>,
+        }
+    }
+
+    unsafe fn drop_original<T>(original : *mut u8) {
+        drop(Box::from_raw(original.cast::<ObjOriginal<T>>()));
+    }
+
+    fn addr(&self) -> usize {
+        self.original.addr()
+    }
+
+    // Only once no section can reach the object anymore.
+    unsafe fn drop_now(self) {
+        (self.drop)(self.original)
+    }
+}
+
+
+unsafe impl<T> Send for Rlu<T> {}
+unsafe impl<T> Sync for Rlu<T> {}
+
+
+#[derive(Debug)]
+pub struct Rlu<T> (
+    *mut ObjOriginal<T>
+);
+
+// What an RLU object must be. Objects of all types share the domain they are
+// allocated in, so they must outlive it and may be dropped on any thread. Readers on
+// many threads share one version of an object at a time, so it must be Sync too.
+pub trait ClonedT : Clone + Send + Sync + 'static {}
+impl<T: Clone + Send + Sync + 'static> ClonedT for T {}
+
+impl<T> Clone for Rlu<T> {
+    fn clone(&self) -> Self{
+        *self
+    }
+}
+
+impl<T> Copy for Rlu<T> {}
+
+// Handles always refer to the original, so this is object identity.
+impl<T> PartialEq for Rlu<T> {
+    fn eq(&self, other: &Rlu<T>) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T> Eq for Rlu<T> {}
+
+/// A field of an RLU object pointing at another one, or at nothing. It always holds
+/// the original, so it compares equal to any other pointer to the same object, no
+/// matter through which copy either was read. Linked structures keep these instead of
+/// boxing `Rlu` handles.
+#[derive(Debug)]
+pub struct RluPtr<T> (
+    *mut ObjOriginal<T>
+);
+
+unsafe impl<T> Send for RluPtr<T> {}
+unsafe impl<T> Sync for RluPtr<T> {}
+
+impl<T> Clone for RluPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for RluPtr<T> {}
+
+impl<T> PartialEq for RluPtr<T> {
+    fn eq(&self, other: &RluPtr<T>) -> bool {
+        rlu_is_same_ptrs(self, other)
+    }
+}
+
+impl<T> Eq for RluPtr<T> {}
+
+impl<T> Default for RluPtr<T> {
+    fn default() -> RluPtr<T> {
+        RluPtr::null()
+    }
+}
+
+impl<T> From<Rlu<T>> for RluPtr<T> {
+    fn from(obj: Rlu<T>) -> RluPtr<T> {
+        RluPtr(obj.0)
+    }
+}
+
+impl<T> From<Option<Rlu<T>>> for RluPtr<T> {
+    fn from(obj: Option<Rlu<T>>) -> RluPtr<T> {
+        obj.map_or(RluPtr::null(), RluPtr::from)
+    }
+}
+
+impl<T> RluPtr<T> {
+    pub const fn null() -> RluPtr<T> {
+        RluPtr(null_mut())
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.0.is_null()
+    }
+
+    /// The object pointed at. The handle is only good until `section` ends: a writer
+    /// that unlinked the object may free it once every section that could reach it
+    /// is over, and `WriteSection::free` makes it promise nobody uses it after that.
+    pub fn load(&self, _section: &ReadSection<'_>) -> Option<Rlu<T>> where T: ClonedT {
+        if self.is_null() {
+            None
+        } else {
+            Some(Rlu(self.0))
+        }
+    }
+
+    /// Points at `obj`, or at nothing. No section is needed: a `&mut RluPtr` of a
+    /// shared object can only be had through `WriteSection::lock`, so this writes our
+    /// private copy, and any other one belongs to an object nobody else can see yet.
+    pub fn assign(&mut self, obj: Option<Rlu<T>>) {
+        rlu_assign_ptr(self, obj)
+    }
+
+    /// Like `assign(None)`, returning the object pointed at before.
+    pub fn take(&mut self) -> Option<Rlu<T>> {
+        let obj = if self.is_null() { None } else { Some(Rlu(self.0)) };
+        *self = RluPtr::null();
+        obj
+    }
+}
+
+/// A version of an object handed out by `ReadSection::version`: the original, our
+/// copy or another writer's copy. Unlike a plain `&T`, it is known to come from a
+/// section, so `ReadSection::same_object` can tell which object it is a version of.
+#[derive(Debug)]
+pub struct RluRef<'a, T> (
+    &'a T
+);
+
+impl<'a, T> Clone for RluRef<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for RluRef<'a, T> {}
+
+impl<'a, T> Deref for RluRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0
+    }
+}
+
+/// Log of the copies a thread locked in its current section, of whatever types. The
+/// copies live in pools of slots, one pool per layout, that grow a chunk at a time
+/// instead of reallocating, because objects point at their copies in here. Chunks are
+/// kept around for the next sections once allocated.
+///
+/// `entries` holds the copies that own their data, in the order they were locked. A
+/// copy gives its data up either by being dropped (`truncate`) or by having it moved
+/// into the original on writeback (`forget_all`). The slot memory itself stays valid
+/// until it is reused, so readers that stole a copy can finish reading it. A slot is
+/// only reused for a copy of the same layout, so its header stays where they look.
+/// Slots are only ever accessed field by field through raw pointers, never as a whole.
+pub struct WriteLog {
+    pools : Vec<CopyPool>,
+    entries : Vec<LogEntry>,
+}
+
+struct LogEntry {
+    copy : *mut u8, // an ObjCopy of the type `vtable` was made for
+    vtable : &'static CopyVTable,
+    pool : usize,
+}
+
+// The slots for copies of one layout, taken and given back in stack order like the
+// entries of the log.
+struct CopyPool {
+    slot : Layout, // of ObjCopy<T>, for every T in the pool
+    chunks : Vec<*mut u8>, // RLU_LOG_CHUNK_SIZE slots each, owned by the pool
+    used : usize,
+}
+
+impl WriteLog {
+    fn new() -> WriteLog {
+        WriteLog {
+            pools: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Fills in the header of the next free slot for a copy of `T`, without taking it
+    // yet.
+    fn reserve<T : ClonedT>(&mut self, thread_id : usize, run_cnt : usize, original : Rlu<T>) -> *mut ObjCopy<T> {
+        let pool = self.pool_of::<T>();
+        let slot = self.pools[pool].next_slot().cast::<ObjCopy<T>>();
+        unsafe {
+            // published by the compare_exchange in rlu_try_lock (5)
+            (*slot).thread_id.store(thread_id, Ordering::Relaxed);
+            (*slot).run_cnt.store(run_cnt, Ordering::Relaxed);
+            ptr::addr_of_mut!((*slot).body.original).write(original.0);
+        }
+        slot
+    }
+
+    // Takes the slot handed out by the last reserve and clones the original into it.
+    fn push<T : ClonedT>(&mut self) {
+        let pool = self.pool_of::<T>();
+        let copy = self.pools[pool].next_slot();
+        let vtable = &ObjCopy::<T>::VTABLE;
+        unsafe { (vtable.clone)(copy) };
+        self.pools[pool].used += 1;
+        self.entries.push(LogEntry { copy, vtable, pool });
+    }
+
+    // Drops the copies from index `len` on.
+    fn truncate(&mut self, len : usize) {
+        while self.entries.len() > len {
+            let entry = self.entries.pop().unwrap();
+            unsafe { (entry.vtable.drop)(entry.copy) };
+            self.pools[entry.pool].used -= 1;
+        }
+    }
+
+    // Empties the log without dropping anything, once writeback moved the data of
+    // every copy out.
+    fn forget_all(&mut self) {
+        self.entries.clear();
+        for pool in self.pools.iter_mut() {
+            pool.used = 0;
+        }
+    }
+
+    fn pool_of<T>(&mut self) -> usize {
+        let slot = Layout::new::<ObjCopy<T>>();
+        match self.pools.iter().position(|pool| pool.slot == slot) {
+            Some(pool) => pool,
+            None => {
+                self.pools.push(CopyPool { slot, chunks: Vec::new(), used: 0 });
+                self.pools.len() - 1
+            }
+        }
+    }
+}
+
+impl Drop for WriteLog {
+    fn drop(&mut self) {
+        // the pools themselves never drop the data in their slots
+        self.truncate(0);
+    }
+}
+
+impl CopyPool {
+    // The next free slot, adding a chunk if all are in use.
+    fn next_slot(&mut self) -> *mut u8 {
+        if self.used == self.chunks.len() * RLU_LOG_CHUNK_SIZE {
+            let chunk = unsafe { alloc::alloc(self.chunk_layout()) };
+            if chunk.is_null() {
+                alloc::handle_alloc_error(self.chunk_layout());
+            }
+            chunk.expose_provenance(); // for rlu_original_of
+            for i in 0..RLU_LOG_CHUNK_SIZE {
+                // the header is the same for every T of the pool
+                unsafe { chunk.add(i * self.slot.size()).cast::<ObjCopy<()>>().write(ObjCopy::empty()) };
+            }
+            self.chunks.push(chunk);
+        }
+
+        let idx = self.used;
+        unsafe { self.chunks[idx / RLU_LOG_CHUNK_SIZE].add(idx % RLU_LOG_CHUNK_SIZE * self.slot.size()) }
+    }
+
+    fn chunk_layout(&self) -> Layout {
+        Layout::from_size_align(self.slot.size() * RLU_LOG_CHUNK_SIZE, self.slot.align()).unwrap()
+    }
+}
+
+impl Drop for CopyPool {
+    fn drop(&mut self) {
+        for &chunk in self.chunks.iter() {
+            unsafe {
+                for i in 0..RLU_LOG_CHUNK_SIZE {
+                    ptr::drop_in_place(chunk.add(i * self.slot.size()).cast::<ObjCopy<()>>());
+                }
+                alloc::dealloc(chunk, self.chunk_layout());
+            }
+        }
+    }
+}
+
+// Keeps what it wraps on cache lines of its own (128 bytes to also cover
+// adjacent-line prefetching).
+#[repr(align(128))]
+struct CachePadded<T>(T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+// Records are handed out one per allocation, so two threads never share a cache
+// line. Within a record the fields are grouped by who writes them, so that a reader
+// entering a section doesn't invalidate the line its copies' readers look at, and
+// writers parking on it don't invalidate the one it writes.
+pub struct RluThreadData {
+    // written by the thread on every section, read by writers in rlu_synchronize
+    section : CachePadded<SectionState>,
+    // written by the thread when it commits, read by readers that find its copies
+    write_clock : CachePadded<AtomicUsize>,
+    // written by other threads
+    requests : CachePadded<Requests>,
+    // cold, kept out of line
+    owner : Box<UnsafeCell<OwnerState>>,
+}
+
+struct SectionState {
+    active : AtomicBool, // false once the thread exited and the slot can be reused
+    claimed : AtomicBool, // someone works on the owner state, see RluThreadData::claim
+    run_cnt : AtomicUsize,
+    local_clock : AtomicUsize,
+    thread_id : usize,
+}
+
+struct Requests {
+    sync_request : AtomicBool, // set by threads that need our deferred copies written back
+    waiters : AtomicUsize, // writers parked until this thread leaves its section
+    wakeup_lock : Mutex<()>,
+    wakeup : Condvar,
+}
+
+// The part of a thread record that only the registered thread itself touches. Other
+// threads read the rest of the record while it runs, so this is reached through
+// `RluThreadData::owner` and never through a `&mut RluThreadData`.
+struct OwnerState {
+    is_writer : bool, // locked something, so the section commits
+    upgraded : bool, // rlu_upgrade was called in the current section
+    depth : usize, // sections open, run_cnt only counts the outermost one
+    write_log : [WriteLog; 2],
+    current_log: usize,
+    free_nodes : Vec<ErasedObj>, // waiting for a commit, then dropped
+    blocked_on : Option<usize>, // thread whose deferred copy a lock ran into
+    section_frees : usize, // length of free_nodes when the current section started
+    section_copies : usize, // size of the write log when the current section started
+}
+
+impl OwnerState {
+    fn log(&mut self) -> &mut WriteLog {
+        &mut self.write_log[self.current_log]
+    }
+
+    fn has_pending_writes(&self) -> bool {
+        self.write_log[self.current_log].len() > 0 || !self.free_nodes.is_empty()
+    }
+}
+
+impl RluThreadData {
+    fn new(thid : usize) -> RluThreadData {  
+        RluThreadData {
+            section: CachePadded(SectionState {
+                active: AtomicBool::new(true),
+                claimed: AtomicBool::new(false),
+                run_cnt: AtomicUsize::new(0),
+                local_clock: AtomicUsize::new(0),
+                thread_id: thid,
+            }),
+            write_clock: CachePadded(AtomicUsize::new(usize::MAX)),
+            requests: CachePadded(Requests {
+                sync_request: AtomicBool::new(false),
+                waiters: AtomicUsize::new(0),
+                wakeup_lock: Mutex::new(()),
+                wakeup: Condvar::new(),
+            }),
+            owner: Box::new(UnsafeCell::new(OwnerState {
+                is_writer: false,
+                upgraded: false,
+                depth: 0,
+                write_log: [WriteLog::new(), WriteLog::new()], // create a current log and a swap log
+                current_log: 0,
+                free_nodes: Vec::new(),
+                blocked_on: None,
+                section_frees: 0,
+                section_copies: 0,
+            })),
+        }
+    }
+
+    /// # Safety
+    /// Only the thread registered on this record may dereference the result, or
+    /// another thread holding the claim, which keeps the registered thread off it
+    /// (see claim). It must not keep a reference made from it across another call
+    /// that makes one again (any rlu_* function).
+    unsafe fn owner(&self) -> *mut OwnerState {
+        self.owner.get()
+    }
+
+    // Keeps everyone else off the owner state. In deferred mode the thread holds
+    // the claim for the whole of its sections, so a writer that needs its copies
+    // written back can do it for the thread while it is idle (see rlu_help_sync).
+    // Writing back for exiting threads and reusing their slots take it too. (8)
+    fn claim(&self) {
+        while !self.try_claim() {
+            yield_now();
+        }
+    }
+
+    fn try_claim(&self) -> bool {
+        self.section.claimed.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() // (8)
+    }
+
+    fn release(&self) {
+        self.section.claimed.store(false, Ordering::Release); // (8)
+    }
+
+    // Prepares a slot left behind by an exited thread for a new one. run_cnt keeps
+    // counting so a writer that snapshotted it before the exit is not confused.
+    // Called under the registration lock, before the slot is handed out again.
+    unsafe fn reset(&self) {
+        // a writer may still be helping the thread that exited
+        self.claim();
+        let owner = &mut *self.owner();
+        owner.is_writer = false;
+        self.write_clock.store(usize::MAX, Ordering::Relaxed);
+        for log in owner.write_log.iter_mut() {
+            log.truncate(0);
+        }
+        owner.current_log = 0;
+        owner.free_nodes.clear();
+        owner.blocked_on = None;
+        self.requests.sync_request.store(false, Ordering::Relaxed);
+        self.release();
+    }
+
+    // Called after run_cnt moved past a section. A parked writer either sees the new
+    // run_cnt before it sleeps or is registered in waiters by the time we look (6).
+    fn wake_waiters(&self) {
+        fence(Ordering::SeqCst);
+        if self.requests.waiters.load(Ordering::Relaxed) > 0 {
+            let _lock = self.requests.wakeup_lock.lock().unwrap();
+            self.requests.wakeup.notify_all();
+        }
+    }
+
+    // Parks the calling writer until this thread's run_cnt is no longer sync_cnt.
+    fn wait_for_section_end(&self, sync_cnt : usize) {
+        self.requests.waiters.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::SeqCst); // (6)
+        let mut lock = self.requests.wakeup_lock.lock().unwrap();
+        while self.section.run_cnt.load(Ordering::Acquire) == sync_cnt { // (3)
+            lock = self.requests.wakeup.wait(lock).unwrap();
+        }
+        drop(lock);
+        self.requests.waiters.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn thread_id(&self)->usize{
+        self.section.thread_id
+    }
+
+    // Only the registered thread itself may ask. It is the only one to move run_cnt,
+    // so a Relaxed load tells, and unlike the owner state it can be read while
+    // another thread writes back for it.
+    fn in_section(&self) -> bool {
+        self.section.run_cnt.load(Ordering::Relaxed) & 0x1 != 0
+    }
+}
+
+/// Append-only registry of thread records. A record never moves once created, so
+/// other threads can keep reading it while the registry grows.
+pub struct ThreadRegistry {
+    chunks : [AtomicPtr<AtomicPtr<RluThreadData>>; RLU_MAX_CHUNKS],
+    n_threads : AtomicUsize, // records below this are initialized, whether active or not
+}
+
+impl ThreadRegistry {
+    fn new() -> ThreadRegistry {
+        ThreadRegistry {
+            chunks: std::array::from_fn(|_| AtomicPtr::new(null_mut())),
+            n_threads: AtomicUsize::new(0),
+        }
+    }
+
+    fn chunk_size(chunk : usize) -> usize {
+        RLU_FIRST_CHUNK_SIZE << chunk
+    }
+
+    // chunk and offset in the chunk of a thread id
+    fn locate(thread_id : usize) -> (usize, usize) {
+        let pos = thread_id + RLU_FIRST_CHUNK_SIZE;
+        let chunk = (pos.ilog2() - RLU_FIRST_CHUNK_SIZE.ilog2()) as usize;
+        (chunk, pos - Self::chunk_size(chunk))
+    }
+
+    /// Number of records, including those of exited threads.
+    pub fn len(&self) -> usize {
+        self.n_threads.load(Ordering::Acquire) // (7)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Appends a record for the next thread id. Callers must be serialized.
+    fn push(&self) -> usize {
+        let thread_id = self.len();
+        let (chunk, offset) = Self::locate(thread_id);
+        assert!(chunk < RLU_MAX_CHUNKS, "Too many threads...");
+
+        let mut slots = self.chunks[chunk].load(Ordering::Acquire);
+        if slots.is_null() {
+            let new_chunk: Box<[AtomicPtr<RluThreadData>]> = (0..Self::chunk_size(chunk))
+                .map(|_| AtomicPtr::new(null_mut()))
+                .collect();
+            slots = Box::into_raw(new_chunk) as *mut AtomicPtr<RluThreadData>;
+            self.chunks[chunk].store(slots, Ordering::Release);
+        }
+
+        let record = Box::into_raw(Box::new(RluThreadData::new(thread_id)));
+        unsafe {
+            (*slots.add(offset)).store(record, Ordering::Release);
+        }
+
+        // publish only once the record is in place
+        self.n_threads.store(thread_id + 1, Ordering::Release);
+        thread_id
+    }
+
+    fn record(&self, thread_id : usize) -> *mut RluThreadData {
+        assert!(thread_id < self.len(), "Thread ID out of bounds...");
+        let (chunk, offset) = Self::locate(thread_id);
+        unsafe {
+            (*self.chunks[chunk].load(Ordering::Acquire).add(offset)).load(Ordering::Acquire)
+        }
+    }
+}
+
+impl Index<usize> for ThreadRegistry {
+    type Output = RluThreadData;
+
+    fn index(&self, thread_id : usize) -> &RluThreadData {
+        unsafe { &*self.record(thread_id) }
+    }
+}
+
+impl Drop for ThreadRegistry {
+    fn drop(&mut self) {
+        for chunk in 0..RLU_MAX_CHUNKS {
+            let slots = self.chunks[chunk].load(Ordering::Relaxed);
+            if slots.is_null() {
+                break;
+            }
+
+            let size = Self::chunk_size(chunk);
+            unsafe {
+                for offset in 0..size {
+                    let record = (*slots.add(offset)).load(Ordering::Relaxed);
+                    if !record.is_null() {
+                        drop(Box::from_raw(record));
+                    }
+                }
+                drop(Box::from_raw(ptr::slice_from_raw_parts_mut(slots, size)));
+            }
+        }
+    }
+}
+
+/// When a writer's copies are written back to the originals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RluMode {
+    /// Every writer commits (synchronize, write back, unlock) when its section ends.
+    Eager,
+    /// RLU-deferred: copies stay locked across sections and are written back in a
+    /// batch once the log reaches `deferred_log_limit`, another thread needs one of
+    /// them, the free list passes its high-water mark, or on `rlu_sync_and_writeback`.
+    /// Other threads keep seeing the originals until then. A thread that needs a copy
+    /// of a thread outside any section writes it back for that thread.
+    Deferred,
+}
+
+/// How `rlu_synchronize` waits for a reader that is still inside its section: spin
+/// `spins` times, then yield `yields` times, then park until the reader leaves the
+/// section (or keep spinning if `park` is false).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RluWaitStrategy {
+    pub spins : usize,
+    pub yields : usize,
+    pub park : bool,
+}
+
+impl RluWaitStrategy {
+    /// Busy-wait only. Lowest latency when every thread has a core to itself.
+    pub fn spin() -> RluWaitStrategy {
+        RluWaitStrategy { spins: 0, yields: 0, park: false }
+    }
+}
+
+impl Default for RluWaitStrategy {
+    fn default() -> RluWaitStrategy {
+        RluWaitStrategy {
+            spins: RLU_WAIT_SPINS,
+            yields: RLU_WAIT_YIELDS,
+            park: true,
+        }
+    }
+}
+
+/// Tuning knobs of an `RluGlobal`, passed to `RluGlobal::new`.
+#[derive(Clone, Debug)]
+pub struct RluConfig {
+    pub mode : RluMode,
+    /// In deferred mode, a section that ends with at least this many copies in the
+    /// write log writes them back.
+    pub deferred_log_limit : usize,
+    /// A section that ends with at least this many objects waiting to be freed
+    /// commits even if it locked nothing, so the free list cannot grow without bound.
+    pub free_high_water : usize,
+    /// Locking one more object once the write log holds this many copies fails with
+    /// `RluError::LogFull`. In deferred mode the log also holds the copies of earlier
+    /// sections that were not written back yet.
+    pub write_log_capacity : usize,
+    /// Freeing one more object once this many wait for a commit fails with
+    /// `RluError::FreeListFull`. At least 1, or nothing could ever be freed.
+    pub free_list_capacity : usize,
+    /// What `write` does when a `lock` fails.
+    pub contention : Arc<dyn ContentionPolicy>,
+    /// How writers wait for readers in `rlu_synchronize`.
+    pub wait : RluWaitStrategy,
+    /// Remember every object handed out by `alloc` until it is freed, so dropping
+    /// the `RluGlobal` can drop whatever is still alive. Costs a lock per alloc and
+    /// per commit that frees something.
+    pub track_allocations : bool,
+    /// Handles like `RluSet` look up the calling thread with `RluGlobal::current_thread`
+    /// instead of registering one thread per handle, so one handle can be shared by
+    /// any number of threads.
+    pub auto_register : bool,
+}
+
+impl Default for RluConfig {
+    fn default() -> RluConfig {
+        RluConfig {
+            mode: RluMode::Eager,
+            deferred_log_limit: RLU_DEFERRED_LOG_LIMIT,
+            free_high_water: RLU_FREE_HIGH_WATER,
+            write_log_capacity: usize::MAX,
+            free_list_capacity: usize::MAX,
+            contention: Arc::new(ImmediateRetry),
+            wait: RluWaitStrategy::default(),
+            track_allocations: false,
+            auto_register: false,
+        }
+    }
+}
+
+/// Why an rlu_* call or a section could not go ahead. Nothing is left half done: the
+/// caller is where it was before the call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RluError {
+    /// The object is locked by thread `owner`, which may be the caller itself in
+    /// deferred mode. The section should be aborted and retried.
+    Conflict { owner : usize },
+    /// The write log holds `RluConfig::write_log_capacity` copies.
+    LogFull,
+    /// `RluConfig::free_list_capacity` objects already wait to be freed.
+    FreeListFull,
+    /// The call needs an open section, or a write section for `rlu_try_lock`.
+    NotInSection,
+    /// The call must be made outside of any section (`rlu_abort`: outside of nested
+    /// ones), and one is open.
+    NestedSection,
+    /// The global is null, or the thread id is not that of a registered thread.
+    BadThread,
+    /// The object passed to `rlu_try_lock` or `rlu_free` is null.
+    NullObject,
+}
+
+impl Display for RluError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RluError::Conflict { owner } => write!(f, "object is locked by thread {owner}"),
+            RluError::LogFull => write!(f, "write log is full"),
+            RluError::FreeListFull => write!(f, "free list is full"),
+            RluError::NotInSection => write!(f, "not inside a section"),
+            RluError::NestedSection => write!(f, "already inside a section"),
+            RluError::BadThread => write!(f, "not a registered thread"),
+            RluError::NullObject => write!(f, "null object"),
+        }
+    }
+}
+
+impl Error for RluError {}
+
+/// Conflict counters of an `RluGlobal`, summed over all threads since it was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RluStats {
+    /// Write sections that were aborted and run again.
+    pub retries : usize,
+    /// Write sections that gave up with `RluError::Conflict`.
+    pub failed_writes : usize,
+}
+
+// Hands out RluGlobal::id. Nothing synchronizes through it, so loom does not model it.
+static NEXT_DOMAIN_ID: AtomicU64 = AtomicU64::new(0);
+
+/// An RLU domain: the clock, the registered threads and their write logs. Objects of
+/// any `ClonedT` type can be allocated in one domain and updated in one section.
+pub struct RluGlobal {
+    id : u64, // unique among all domains of the process, never reused
+    global_clock : AtomicUsize,
+    registration : Mutex<()>,
+    threads : ThreadRegistry,
+    config : RluConfig,
+    retries : AtomicUsize,
+    failed_writes : AtomicUsize,
+    callbacks : Mutex<Vec<Box<dyn FnOnce() + Send>>>, // run after the next grace period
+    reclaimer : Mutex<Option<Reclaimer>>,
+    live : Mutex<HashMap<usize, ErasedObj>>, // allocated objects by address, with track_allocations
+}
+
+// Background thread that drops freed objects handed to it by rlu_process_free.
+// Everything it receives has already been through a grace period.
+struct Reclaimer {
+    batches : Sender<Vec<ErasedObj>>,
+    handle : JoinHandle<()>,
+}
+
+impl RluGlobal {
+    /// Creates a domain owned by the caller. Dropping it waits for open sections to
+    /// end, writes back and frees what registered threads left pending, and with
+    /// `track_allocations` drops every object that was never freed. Panics if
+    /// `config.free_list_capacity` is 0.
+    pub fn new(config : RluConfig) -> RluGlobal {
+        assert!(config.free_list_capacity > 0, "free_list_capacity must be at least 1");
+
+        RluGlobal {
+            id: NEXT_DOMAIN_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+            global_clock: AtomicUsize::new(0),
+            registration: Mutex::new(()),
+            threads: ThreadRegistry::new(),
+            config,
+            retries: AtomicUsize::new(0),
+            failed_writes: AtomicUsize::new(0),
+            callbacks: Mutex::new(Vec::new()),
+            reclaimer: Mutex::new(None),
+            live: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a domain for the raw rlu_* API. It lives until passed to `destroy`.
+    pub fn init() -> *mut RluGlobal {
+        RluGlobal::init_with_config(RluConfig::default())
+    }
+
+    pub fn init_with_config(config : RluConfig) -> *mut RluGlobal {
+        let boxed = Box::new(RluGlobal::new(config));
+        Box::into_raw(boxed)
+    }
+
+    /// Tears down a domain created by `init`, like dropping one created by `new`.
+    ///
+    /// # Safety
+    ///
+    /// `g_rlu` must come from `init` or `init_with_config` and must not be used
+    /// afterwards, by this or any other thread.
+    pub unsafe fn destroy(g_rlu : *mut RluGlobal) {
+        drop(Box::from_raw(g_rlu));
+    }
+
+    pub fn config(&self) -> &RluConfig {
+        &self.config
+    }
+
+    /// Identifies this domain: unique among all domains of the process, never reused.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The token of `thread_id`, a thread registered with this domain through
+    /// `rlu_thread_init`. `RluThread::token` and `current_thread` hand out tokens
+    /// safely.
+    ///
+    /// # Safety
+    ///
+    /// `thread_id` must stay registered while the token is alive, and no other
+    /// thread may use it in the meantime, through a token or the raw API.
+    pub unsafe fn token(&self, thread_id: usize) -> RluThreadToken<'_> {
+        RluThreadToken { thread_id, domain_id: self.id, registration: PhantomData }
+    }
+
+    // The raw thread id behind `thread`, which must belong to this domain.
+    fn thread_id_of(&self, thread: RluThreadToken<'_>) -> usize {
+        assert_eq!(
+            thread.domain_id, self.id,
+            "a thread of domain {} was used with domain {}", thread.domain_id, self.id
+        );
+        thread.thread_id
+    }
+
+    pub fn stats(&self) -> RluStats {
+        RluStats {
+            retries: self.retries.load(Ordering::Relaxed),
+            failed_writes: self.failed_writes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Allocates an object of any type. Objects of different types can be locked in
+    /// the same section and commit together.
+    pub fn alloc<T : ClonedT>(&self, data : T) -> Rlu<T> {
+        let obj = Rlu(
+            Box::into_raw(
+                Box::new(ObjOriginal {
+                    copy: AtomicPtr::new(ptr::null_mut()),
+                    domain: self.id,
+                    body: ObjData {
+                        original: null_mut(),
+                        data,
+                    },
+                    }
+                )
+            )
+        );
+        unsafe { (*obj.0).body.original = obj.0 };
+        obj.0.expose_provenance(); // for rlu_original_of
+
+        if self.config.track_allocations {
+            self.live.lock().unwrap().insert(obj.0.addr(), ErasedObj::new(obj));
+        }
+        obj
+    }
+
+    /// Registers the calling thread and returns a handle to open sections with.
+    pub fn register(&self) -> RluThread<'_> {
+        RluThread {
+            global: self,
+            thread_id: rlu_thread_init(self.as_ptr()),
+            not_sync: PhantomData,
+        }
+    }
+
+    /// Opens a section for an already registered `thread`. Fails with
+    /// `RluError::NestedSection` if the thread is in a section already: a nested
+    /// section is opened from the outer one with `ReadSection::nest`. So code that
+    /// opens its own sections from a token, a library for example, cannot be called
+    /// from inside a section of the same thread; it has to take a section instead,
+    /// which the caller opens with `nest`. The section borrows `thread`, and with it the `RluThread` the token
+    /// came from.
+    pub fn read<'a>(&'a self, thread: RluThreadToken<'a>) -> Result<ReadSection<'a>, RluError> {
+        let thread_id = self.thread_id_of(thread);
+        if rlu_section_depth(self.as_ptr(), thread_id)? > 0 {
+            return Err(RluError::NestedSection);
+        }
+        rlu_reader_lock(self.as_ptr(), thread_id)?;
+        Ok(ReadSection { global: self, thread_id, nested: false, not_send: PhantomData })
+    }
+
+    /// Waits for a grace period: returns once every section that was open when it
+    /// was called has ended, then runs the callbacks queued before the call with
+    /// `call_after_grace_period`. The caller does not need to be registered, but must
+    /// not be inside a section itself or it waits for itself forever.
+    pub fn synchronize(&self) {
+        let callbacks = mem::take(&mut *self.callbacks.lock().unwrap());
+
+        for (i, sync_cnt) in self.readers_in_section(None) {
+            rlu_wait_for_reader(self.config.wait, &self.threads[i], sync_cnt, || false);
+        }
+
+        for callback in callbacks {
+            callback();
+        }
+    }
+
+    /// Queues `callback` to run after the next grace period, e.g. to free data that
+    /// readers may still be looking at. Callbacks run on the thread that calls
+    /// `synchronize`. Safe to call from inside a section.
+    pub fn call_after_grace_period<F>(&self, callback: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.callbacks.lock().unwrap().push(Box::new(callback));
+    }
+
+    /// Starts a background thread that drops freed objects once their grace period is
+    /// over, instead of the writer dropping them at the end of its commit. Does
+    /// nothing if it is already running.
+    pub fn start_reclaimer(&self) {
+        let mut reclaimer = self.reclaimer.lock().unwrap();
+        if reclaimer.is_some() {
+            return;
+        }
+
+        let (batches, received) = mpsc::channel::<Vec<ErasedObj>>();
+        let handle = thread::Builder::new()
+            .name(String::from("rlu-reclaimer"))
+            .spawn(move || {
+                // ends once stop_reclaimer dropped the sender and the channel is empty
+                for batch in received {
+                    for obj in batch {
+                        unsafe { obj.drop_now() };
+                    }
+                }
+            })
+            .expect("failed to spawn the reclaimer thread");
+
+        *reclaimer = Some(Reclaimer { batches, handle });
+    }
+
+    /// Stops the reclaimer thread after it dropped everything handed to it so far.
+    /// Later frees are dropped by the writers again.
+    pub fn stop_reclaimer(&self) {
+        let reclaimer = self.reclaimer.lock().unwrap().take();
+        if let Some(Reclaimer { batches, handle }) = reclaimer {
+            drop(batches);
+            handle.join().expect("the reclaimer thread panicked");
+        }
+    }
+
+    // Snapshot of the (thread_id, run_cnt) of every thread other than `skip` that is
+    // inside a section. Exited threads are never inside one.
+    fn readers_in_section(&self, skip : Option<usize>) -> Vec<(usize, usize)> {
+        // pairs with the fence in rlu_reader_lock: a reader we miss here reads the
+        // global clock after our caller moved it (1)
+        fence(Ordering::SeqCst);
+        (0..self.threads.len())
+            .filter(|&i| Some(i) != skip && self.threads[i].section.active.load(Ordering::Acquire)) // (3)
+            .map(|i| (i, self.threads[i].section.run_cnt.load(Ordering::Acquire))) // (3)
+            .filter(|&(_, cnt)| cnt & 0x1 != 0)
+            .collect()
+    }
+
+    /// Runs `body` as a write section for an already registered `thread` and
+    /// returns its result. `body` passes on the errors of `lock` and `free` with `?`.
+    /// On `RluError::Conflict` the section is aborted and, if the configured
+    /// `ContentionPolicy` agrees, `body` runs again from the start, so it should not
+    /// have side effects outside the section. Any other error aborts the section and
+    /// is returned as is. On `Ok` the section ends and commits. Like `read`, it fails
+    /// with `RluError::NestedSection` inside a section of the same thread, where
+    /// `WriteSection::nest` hands out a nested section instead.
+    pub fn write<'a, R, F>(&'a self, thread: RluThreadToken<'a>, mut body: F) -> Result<R, RluError>
+    where
+        F: FnMut(&mut WriteSection<'_>) -> Result<R, RluError>,
+    {
+        let mut attempts = 0;
+        loop {
+            let mut section = self.read(thread)?.upgrade();
+            let err = match body(&mut section) {
+                Ok(ret) => return Ok(ret),
+                Err(err) => err,
+            };
+            section.abort();
+
+            if !matches!(err, RluError::Conflict { .. }) {
+                return Err(err);
+            }
+
+            attempts += 1;
+            if !self.config.contention.on_conflict(attempts) {
+                self.failed_writes.fetch_add(1, Ordering::Relaxed);
+                return Err(err);
+            }
+            self.retries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Runs `body` as one write section spanning this domain and `other`, like
+    /// `write`: a conflict in either domain aborts both sections and retries `body`
+    /// under this domain's `ContentionPolicy`, so on `Err` neither domain changed.
+    ///
+    /// Each domain still commits on its own clock. A reader of both domains may see
+    /// the update of one before the update of the other, so objects that must change
+    /// together for readers belong in one domain. The sections end in the order of
+    /// the domain ids: a writer ending them the other way round could wait for our
+    /// section in one domain while we wait for its section in the other.
+    ///
+    /// Panics if `other` is this domain: committing either section would wait for the
+    /// other one to end. `write` covers a single domain.
+    pub fn write_across<'a, 'b, R, F>(
+        &'a self,
+        thread: RluThreadToken<'a>,
+        other: &'b RluGlobal,
+        other_thread: RluThreadToken<'b>,
+        mut body: F,
+    ) -> Result<R, RluError>
+    where
+        F: FnMut(&mut WriteSection<'_>, &mut WriteSection<'_>) -> Result<R, RluError>,
+    {
+        assert_ne!(self.id, other.id, "write_across needs two different domains");
+        let ours_first = self.id <= other.id;
+        let mut attempts = 0;
+        loop {
+            let mut ours = self.read(thread)?.upgrade();
+            let mut theirs = match other.read(other_thread) {
+                Ok(section) => section.upgrade(),
+                Err(err) => {
+                    ours.abort();
+                    return Err(err);
+                }
+            };
+            let err = match body(&mut ours, &mut theirs) {
+                Ok(ret) => {
+                    if ours_first {
+                        drop(ours);
+                        drop(theirs);
+                    } else {
+                        drop(theirs);
+                        drop(ours);
+                    }
+                    return Ok(ret);
+                }
+                Err(err) => err,
+            };
+            // in deferred mode an abort may commit, so it keeps the order too
+            if ours_first {
+                ours.abort();
+                theirs.abort();
+            } else {
+                theirs.abort();
+                ours.abort();
+            }
 
+            if !matches!(err, RluError::Conflict { .. }) {
+                return Err(err);
+            }
 
+            attempts += 1;
+            if !self.config.contention.on_conflict(attempts) {
+                self.failed_writes.fetch_add(1, Ordering::Relaxed);
+                return Err(err);
+            }
+            self.retries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 
-macro_rules! debug_log {
-    ($($rest:tt)*) => {
-        #[cfg(debug_assertions)]
-        std::println!($($rest)*);
+    // Writes back and frees what registered threads have not committed in deferred
+    // mode, for whoever tears down the objects. None of the threads may be in a
+    // section. The registration lock keeps them from exiting, and so from committing
+    // on their own, in the meantime.
+    pub(crate) fn sync_all(&self) {
+        let _registration = self.registration.lock().unwrap();
+        let g_rlu = self.as_ptr();
+        for i in 0..self.threads.len() {
+            let thread_data = &self.threads[i];
+            if thread_data.section.active.load(Ordering::Relaxed) {
+                thread_data.claim();
+                sync_and_writeback(g_rlu, i);
+                thread_data.release();
+            }
+        }
+    }
+
+    // The rlu_* functions predate the safe layer and take the global by pointer.
+    pub(crate) fn as_ptr(&self) -> *mut RluGlobal {
+        self as *const RluGlobal as *mut RluGlobal
     }
 }
 
+impl RluGlobal {
+    /// The token of the calling thread in `domain`, registering the thread on the
+    /// first call. The thread is unregistered when it exits, unless the domain is gone
+    /// by then: holding the domain through an `Arc` lets the thread tell.
+    pub fn current_thread(domain: &Arc<RluGlobal>) -> RluThreadToken<'_> {
+        let thread_id = AUTO_THREADS.with(|threads| {
+            let threads = &mut threads.borrow_mut().0;
+            if let Some(auto) = threads.iter().find(|auto| auto.domain_id == domain.id) {
+                return auto.thread_id;
+            }
 
-#[derive(Debug)]
-pub struct ObjOriginal<T> {
-    pub copy : AtomicPtr<ObjCopy<T>>,
-    pub data : T,
+            // forget the domains that were dropped in the meantime
+            threads.retain(|auto| auto.domain.strong_count() > 0);
+
+            let thread_id = rlu_thread_init(domain.as_ptr());
+            let weak = Arc::downgrade(domain);
+            threads.push(AutoThread { domain_id: domain.id, thread_id, domain: weak });
+            thread_id
+        });
+        // registered until this thread exits, and the token cannot leave it
+        unsafe { domain.token(thread_id) }
+    }
 }
 
+// The registrations made by RluGlobal::current_thread on this thread.
+struct AutoThreads(Vec<AutoThread>);
 
-#[derive(Debug)]
-pub struct ObjCopy<T> {
+struct AutoThread {
+    domain_id : u64,
     thread_id : usize,
-    original : Rlu<T>,
-    data : T,
+    domain : Weak<RluGlobal>,
 }
 
+impl Drop for AutoThreads {
+    fn drop(&mut self) {
+        for auto in self.0.drain(..) {
+            // may drop the domain, if all other references are gone by now
+            if let Some(domain) = auto.domain.upgrade() {
+                // fails only if the thread left a section open through the raw API,
+                // and then the slot is lost anyway
+                let _ = rlu_thread_exit(domain.as_ptr(), auto.thread_id);
+            }
+        }
+    }
+}
 
-unsafe impl<T> Send for Rlu<T> {}
-unsafe impl<T> Sync for Rlu<T> {}
+thread_local! {
+    static AUTO_THREADS: RefCell<AutoThreads> = const { RefCell::new(AutoThreads(Vec::new())) };
+}
 
+impl Drop for RluGlobal {
+    fn drop(&mut self) {
+        self.stop_reclaimer();
 
-#[derive(Debug)]
-pub struct Rlu<T> (
-    *mut ObjOriginal<T>
-);
+        // sections opened through the raw API may still be running
+        self.synchronize();
 
-pub trait ClonedT : Clone {} 
-impl<T: Clone> ClonedT for T {}
+        self.sync_all();
 
-impl<T> Clone for Rlu<T> {
-    fn clone(&self) -> Self{
-        *self
+        // queued while writing back, nobody is left to read the data anyway
+        for callback in mem::take(self.callbacks.get_mut().unwrap()) {
+            callback();
+        }
+
+        if self.config.track_allocations {
+            for (_, obj) in self.live.get_mut().unwrap().drain() {
+                unsafe { obj.drop_now() };
+            }
+        }
     }
 }
 
-impl<T> Copy for Rlu<T> {}
+/// A thread registered with an `RluGlobal`. Dropping it unregisters the thread and
+/// frees its slot for reuse. It can move to another thread, but not be shared: its
+/// tokens would let two threads use the slot at once.
+pub struct RluThread<'a> {
+    global: &'a RluGlobal,
+    thread_id: usize,
+    not_sync: PhantomData<Cell<()>>,
+}
 
-impl<T> Rlu<T> {
-    pub fn deref(&self) -> &ObjOriginal<T>{
-        unsafe {
-            &*self.0
-        }
+impl<'a> RluThread<'a> {
+    pub fn thread_id(&self) -> usize {
+        self.thread_id
     }
 
-    pub fn deref_mut(&self) -> &mut ObjOriginal<T> {
-        unsafe {
-            &mut *self.0
-        }
+    /// The token of this thread, for `RluGlobal::read` and `RluGlobal::write`. The
+    /// handle stays borrowed while the token is alive.
+    pub fn token(&self) -> RluThreadToken<'_> {
+        // the slot is ours until the handle is dropped, and the handle is not Sync
+        unsafe { self.global.token(self.thread_id) }
+    }
+
+    /// Opens a section. Borrowing the handle mutably keeps sections opened through it
+    /// from nesting, `ReadSection::nest` nests them.
+    pub fn read(&mut self) -> ReadSection<'_> {
+        self.global.read(self.token()).expect(OWN_THREAD)
+    }
+
+    /// Runs `body` as a write section, retrying it until it gets all its locks.
+    /// See `RluGlobal::write`.
+    pub fn write<R, F>(&mut self, body: F) -> Result<R, RluError>
+    where
+        F: FnMut(&mut WriteSection<'_>) -> Result<R, RluError>,
+    {
+        self.global.write(self.token(), body)
+    }
+
+    /// Writes back everything this thread still holds locked in deferred mode.
+    pub fn sync_and_writeback(&mut self) {
+        rlu_sync_and_writeback(self.global.as_ptr(), self.thread_id).expect(OWN_THREAD);
     }
 }
-pub struct WriteLog<T> {
-    log : [ObjCopy<T>; RLU_MAX_LOG_SIZE],
-    curr_size : usize,
+
+/// A thread registered with one domain, as the safe layer takes it. `thread_id` is
+/// the id of the raw API. The token remembers its domain, and using it with another
+/// domain panics. It borrows the registration and cannot leave the thread it was
+/// handed to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RluThreadToken<'a> {
+    thread_id: usize,
+    domain_id: u64,
+    registration: PhantomData<(&'a RluGlobal, *const ())>, // neither Send nor Sync
 }
 
-impl<T> WriteLog<T> {
-    fn new() -> WriteLog<T> {
-        WriteLog {
-            log: unsafe{ MaybeUninit::uninit().assume_init() },
-            curr_size: 0,
-        }
+impl<'a> RluThreadToken<'a> {
+    pub fn thread_id(&self) -> usize {
+        self.thread_id
     }
 }
 
-pub struct RluThreadData<T> {
-    is_writer : bool,
-    write_clock : AtomicUsize,
-    local_clock : AtomicUsize,
-    run_cnt : AtomicUsize,
-    write_log : [WriteLog<T>; 2],
-    current_log: usize,
-    thread_id : usize,
-    free_nodes : [Rlu<T>; RLU_MAX_FREE_NODES],
-    free_nodes_size : usize,
+/// An open section that only reads: it hands out `&T` and nothing else. `upgrade`
+/// turns it into a `WriteSection`. Dropping it ends the section. References handed
+/// out by the section cannot outlive it, and it cannot leave the thread it belongs to.
+pub struct ReadSection<'a> {
+    global: &'a RluGlobal,
+    thread_id: usize,
+    nested: bool, // opened inside another section, see rlu_reader_lock
+    not_send: PhantomData<*const ()>, // run_cnt is only the owner's to move
 }
 
-impl<T> RluThreadData<T> {
-    fn new(thid : usize) -> RluThreadData<T> {  
-        let mut thread = RluThreadData {
-            is_writer: false,
-            write_clock: AtomicUsize::new(usize::MAX),
-            local_clock: AtomicUsize::new(0),
-            run_cnt: AtomicUsize::new(0),
-            write_log: [WriteLog::new(), WriteLog::new()], // create a current log and a swap log
-            current_log: 0,
-            thread_id: thid,
-            free_nodes: unsafe{MaybeUninit::uninit().assume_init()},
-            free_nodes_size: 0,
-        };
-
+/// An open section that may write. Besides everything a `ReadSection` does, it locks
+/// objects to get private copies to write, and frees objects. Dropping it ends the
+/// section and commits the write log.
+pub struct WriteSection<'a> {
+    read: ReadSection<'a>,
+}
 
-        for i in 0..2 {
-            thread.write_log[i].curr_size = 0; // initialize size of write logs as 0
-        }
+// The safe layer only fails if the raw API was used on the same thread id behind its back.
+const OWN_THREAD: &str = "a section was opened or ended through the raw API";
 
-        thread
+impl<'a> Drop for RluThread<'a> {
+    fn drop(&mut self) {
+        rlu_thread_exit(self.global.as_ptr(), self.thread_id).expect(OWN_THREAD);
     }
+}
 
-    pub fn thread_id(&self)->usize{
+impl<'a> ReadSection<'a> {
+    pub fn thread_id(&self) -> usize {
         self.thread_id
     }
+
+    /// Returns the version of `obj` this section should see: our own copy if we locked
+    /// it, a committed copy of another writer, or the original.
+    pub fn dereference<T : ClonedT>(&self, obj: &Rlu<T>) -> &T {
+        let ptr = rlu_dereference(self.global.as_ptr(), self.thread_id, obj as *const Rlu<T> as *mut Rlu<T>);
+        unsafe { &*ptr.expect(OWN_THREAD) }
+    }
+
+    /// Opens a section nested in this one, e.g. for a library call that takes a
+    /// section. It sees the same snapshot and only the end of the outermost section
+    /// commits: see `rlu_reader_lock`. This section is borrowed until the nested one
+    /// ends, so what either hands out never overlaps.
+    pub fn nest(&mut self) -> ReadSection<'_> {
+        rlu_reader_lock(self.global.as_ptr(), self.thread_id).expect(OWN_THREAD);
+        ReadSection { global: self.global, thread_id: self.thread_id, nested: true, not_send: PhantomData }
+    }
+
+    /// Like `dereference`, for comparing with `same_object`.
+    pub fn version<T : ClonedT>(&self, obj: &Rlu<T>) -> RluRef<'_, T> {
+        RluRef(self.dereference(obj))
+    }
+
+    /// Makes this a write section, which commits when it ends. What was read so far
+    /// stays valid: the section does not restart.
+    pub fn upgrade(self) -> WriteSection<'a> {
+        rlu_upgrade(self.global.as_ptr(), self.thread_id).expect(OWN_THREAD);
+        WriteSection { read: self }
+    }
+
+    /// Whether `a` and `b` are versions of the same object. See `rlu_same_object`.
+    pub fn same_object<T : ClonedT>(&self, a: RluRef<'_, T>, b: RluRef<'_, T>) -> bool {
+        // both come from sections that are still open, as their lifetimes tell
+        unsafe { rlu_same_object(a.0, b.0) }
+    }
 }
 
-pub struct RluGlobal<T : ClonedT> {
-    global_clock : AtomicUsize,
-    n_threads : AtomicUsize,
-    threads : [RluThreadData<T> ; RLU_MAX_THREADS],
+// Reading is the same in both kinds of section.
+impl<'a> Deref for WriteSection<'a> {
+    type Target = ReadSection<'a>;
+
+    fn deref(&self) -> &ReadSection<'a> {
+        &self.read
+    }
 }
 
-impl<T : ClonedT> RluGlobal<T> {
-    fn new() -> RluGlobal<T> {
-        
-        RluGlobal {
-            global_clock: AtomicUsize::new(0),
-            n_threads: AtomicUsize::new(0),
-            threads: unsafe {MaybeUninit::uninit().assume_init()},
+impl<'a> WriteSection<'a> {
+    /// Locks `obj` for writing and returns our private copy of it. On
+    /// `RluError::Conflict` another thread holds the lock, and the section should be
+    /// aborted and retried.
+    pub fn lock<T : ClonedT>(&mut self, obj: &Rlu<T>) -> Result<&mut T, RluError> {
+        rlu_try_lock(self.read.global.as_ptr(), self.read.thread_id, obj as *const Rlu<T> as *mut Rlu<T>)
+            .map(|ptr| unsafe { &mut *ptr })
+    }
+
+    /// Opens a write section nested in this one, like `ReadSection::nest`. What it
+    /// locks joins our write log.
+    pub fn nest(&mut self) -> WriteSection<'_> {
+        WriteSection { read: self.read.nest() }
+    }
+
+    /// Frees `obj` once the section commits and no reader can still see it.
+    ///
+    /// # Safety
+    ///
+    /// `obj` must be unlinked by this section, so sections that start after it
+    /// commits cannot reach it, and no handle to it may be used after that. It must
+    /// be freed only once, and no other thread may hold it locked.
+    pub unsafe fn free<T : ClonedT>(&mut self, obj: Rlu<T>) -> Result<(), RluError> {
+        let mut obj = obj;
+        rlu_free(self.read.global.as_ptr(), self.read.thread_id, &mut obj)
+    }
+
+    /// Ends the section without committing anything locked in it. A nested section
+    /// is only left: what gets committed is up to the outermost one.
+    pub fn abort(self) {
+        if self.nested {
+            return;
+        }
+        rlu_abort(self.read.global.as_ptr(), self.read.thread_id).expect(OWN_THREAD);
+        mem::forget(self);
+    }
+}
+
+impl<'a> Drop for ReadSection<'a> {
+    fn drop(&mut self) {
+        // while unwinding, a second panic would abort the process
+        if !thread::panicking() {
+            rlu_reader_unlock(self.global.as_ptr(), self.thread_id).expect(OWN_THREAD);
+        } else if self.nested {
+            // the outermost section aborts on its way out
+            let _ = rlu_reader_unlock(self.global.as_ptr(), self.thread_id);
+        } else {
+            // don't publish half-done writes
+            let _ = rlu_abort(self.global.as_ptr(), self.thread_id);
         }
     }
+}
 
-    pub fn init() -> *mut RluGlobal<T> {
-        let boxed = Box::new(RluGlobal::new());
-        Box::into_raw(boxed)
+// The domain and the record of `thread_id`, if it is a registered thread of `g_rlu`.
+unsafe fn rlu_thread<'a>(g_rlu : *mut RluGlobal, thread_id : usize) -> Result<(&'a RluGlobal, &'a RluThreadData), RluError> {
+    if g_rlu.is_null() || thread_id >= (*g_rlu).threads.len() {
+        return Err(RluError::BadThread);
     }
 
-    pub fn alloc(&self, data : T) -> Rlu<T> {
-        Rlu(
-            Box::into_raw(
-                Box::new(ObjOriginal {
-                    copy: AtomicPtr::new(ptr::null_mut()),
-                    data,
-                    }
-                )
-            )
-        )
+    let rlu_global = &*g_rlu;
+    let thread_data = &rlu_global.threads[thread_id];
+    if !thread_data.section.active.load(Ordering::Relaxed) {
+        return Err(RluError::BadThread);
     }
+    Ok((rlu_global, thread_data))
+}
 
-    
-        
+// The rlu_* functions mirror librlu and take raw pointers to the global and to objects.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_thread_init(rlu_global: *mut RluGlobal) -> usize {
+    unsafe {
+        let rlu_global = &*rlu_global;
+        let _registration = rlu_global.registration.lock().unwrap();
+
+        // reuse the slot of a thread that exited
+        let n = rlu_global.threads.len();
+        for thread_id in 0..n {
+            let thread_data = &rlu_global.threads[thread_id];
+            // pairs with the Release in rlu_thread_exit, the slot is all ours after it
+            if !thread_data.section.active.load(Ordering::Acquire) {
+                thread_data.reset();
+                thread_data.section.active.store(true, Ordering::Relaxed); // (1)
+                return thread_id;
+            }
+        }
+
+        rlu_global.threads.push()
     }
+}
 
-pub fn rlu_thread_init<T : ClonedT> (rlu_global: *mut RluGlobal<T>) -> usize {
+/// Unregisters `thread_id` so its slot can be handed out by a later `rlu_thread_init`.
+/// Must be called outside of a section. Objects still waiting in the free list are
+/// reclaimed first, and sections open on other threads are waited for.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_thread_exit(g_rlu: *mut RluGlobal, thread_id: usize) -> Result<(), RluError> {
+    debug_log!("Thread {thread_id}: exit");
     unsafe {
-        let thread_id = (*rlu_global).n_threads.fetch_add(1, Ordering::SeqCst);
-        assert!(thread_id < RLU_MAX_THREADS);
-        
-        let thread_data = RluThreadData::new(thread_id);
-        (*rlu_global).threads[thread_id] = thread_data;
-        
-        thread_id
+        let (rlu_global, thread_data) = rlu_thread(g_rlu, thread_id)?;
+        if thread_data.in_section() {
+            return Err(RluError::NestedSection);
+        }
+
+        // nobody else can get at deferred copies once we are gone, and sync_all must
+        // not write them back at the same time
+        {
+            let _registration = rlu_global.registration.lock().unwrap();
+            thread_data.claim();
+            sync_and_writeback(g_rlu, thread_id);
+            thread_data.release();
+        }
+
+        // readers that stole copies of our last commit were not waited for, and the
+        // next owner of the slot reuses both logs right away
+        for (i, sync_cnt) in rlu_global.readers_in_section(Some(thread_id)) {
+            rlu_wait_for_reader(rlu_global.config.wait, &rlu_global.threads[i], sync_cnt, || false);
+        }
+
+        thread_data.section.active.store(false, Ordering::Release); // (3)
     }
+    Ok(())
 }
 
-pub fn rlu_reader_lock<T : ClonedT>(g_rlu: *mut RluGlobal<T>, thread_id: usize) {
+/// Opens a section for `thread_id`. Inside a section this opens a nested one, which is
+/// part of the outer one: it sees the same snapshot, what it locks joins the outer
+/// write log, and only the end of the outermost section commits.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_reader_lock(g_rlu: *mut RluGlobal, thread_id: usize) -> Result<(), RluError> {
     debug_log!("Thread {thread_id}: lock");
     unsafe {
-        if !g_rlu.is_null() { // Safety check
-            if thread_id < RLU_MAX_THREADS {
-                let rlu_global = &mut *g_rlu;
-                let thread_data = &mut rlu_global.threads[thread_id];
-                
-                assert_eq!(thread_data.run_cnt.load(Ordering::SeqCst) & 0x1, 0);
-
-                thread_data.is_writer = false;
-                thread_data.run_cnt.fetch_add(1, Ordering::SeqCst);
-                thread_data.local_clock.store(rlu_global.global_clock.load(Ordering::SeqCst), Ordering::SeqCst);
-            }
-        } else {
-            panic!("Thread ID out of bounds...");
+        let (rlu_global, thread_data) = rlu_thread(g_rlu, thread_id)?;
+        if thread_data.in_section() {
+            (*thread_data.owner()).depth += 1;
+            return Ok(());
+        }
+
+        // held until the section ends, see rlu_maybe_sync
+        if rlu_global.config.mode == RluMode::Deferred {
+            thread_data.claim();
         }
+
+        // another thread is waiting for one of our deferred copies
+        if thread_data.requests.sync_request.load(Ordering::Relaxed) {
+            sync_and_writeback(g_rlu, thread_id);
+        }
+
+        let owner = &mut *thread_data.owner();
+        owner.is_writer = false;
+        owner.upgraded = false;
+        owner.depth = 1;
+        owner.section_frees = owner.free_nodes.len();
+        owner.section_copies = owner.log().len();
+        thread_data.section.run_cnt.fetch_add(1, Ordering::Relaxed);
+        // pairs with the fence in readers_in_section: either a committing
+        // writer waits for us or we read its new clock and see its copies (1)
+        fence(Ordering::SeqCst);
+        thread_data.section.local_clock.store(rlu_global.global_clock.load(Ordering::Acquire), Ordering::Release); // (2), (3)
     }
+    Ok(())
 }
 
-pub fn rlu_reader_unlock<T : ClonedT>(g_rlu: *mut RluGlobal<T>, thread_id: usize) {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_reader_unlock(g_rlu: *mut RluGlobal, thread_id: usize) -> Result<(), RluError> {
     debug_log!("Thread {thread_id}: unlock");
     unsafe {
-        if !g_rlu.is_null() { // Safety check
-            if thread_id < RLU_MAX_THREADS {
-                let rlu_global = &mut *g_rlu;
-                let thread_data = &mut rlu_global.threads[thread_id];
-
-                assert_ne!(thread_data.run_cnt.load(Ordering::SeqCst) & 0x1, 0);
-                thread_data.run_cnt.fetch_add(1, Ordering::SeqCst);
-                if thread_data.is_writer {
-                    thread_data.is_writer = false;
-                    rlu_commit_write_log(g_rlu, thread_id);
-                }
-            } else {
-                panic!("Thread ID out of bounds...");
-            }
+        let (rlu_global, thread_data) = rlu_thread(g_rlu, thread_id)?;
+        if !thread_data.in_section() {
+            return Err(RluError::NotInSection);
+        }
+
+        // a nested section leaves the rest to the outermost one
+        (*thread_data.owner()).depth -= 1;
+        if (*thread_data.owner()).depth > 0 {
+            return Ok(());
+        }
+
+        thread_data.section.run_cnt.fetch_add(1, Ordering::Release); // (3)
+        thread_data.wake_waiters();
+
+        let owner = &mut *thread_data.owner();
+        let is_writer = mem::replace(&mut owner.is_writer, false);
+        let pending_frees = owner.free_nodes.len();
+        if rlu_global.config.mode == RluMode::Deferred {
+            rlu_maybe_sync(g_rlu, thread_id);
+        } else if is_writer {
+            rlu_commit_write_log(g_rlu, thread_id);
+        } else if pending_frees >= rlu_global.config.free_high_water {
+            // nothing to write back, but the pending frees need a grace period
+            rlu_commit_write_log(g_rlu, thread_id);
+        }
+    }
+    Ok(())
+}
+
+/// How many sections `thread_id` has open: 0 outside of any, 1 in an outermost one.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_section_depth(g_rlu: *mut RluGlobal, thread_id: usize) -> Result<usize, RluError> {
+    unsafe {
+        let (_, thread_data) = rlu_thread(g_rlu, thread_id)?;
+        if !thread_data.in_section() {
+            return Ok(0);
+        }
+        Ok((*thread_data.owner()).depth)
+    }
+}
+
+/// Makes the current section of `thread_id` a write section, so it may call
+/// `rlu_try_lock`. What it reads before stays valid. In a nested section this is
+/// the whole section, up to the outermost one.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_upgrade(g_rlu: *mut RluGlobal, thread_id: usize) -> Result<(), RluError> {
+    debug_log!("Thread {thread_id}: upgrade");
+    unsafe {
+        let (_, thread_data) = rlu_thread(g_rlu, thread_id)?;
+        if !thread_data.in_section() {
+            return Err(RluError::NotInSection);
         }
+        (*thread_data.owner()).upgraded = true;
     }
+    Ok(())
 }
 
-pub fn rlu_dereference<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_id : usize, obj : *mut Rlu<T>) -> *mut T {
+/// The version of `obj` the current section of `thread_id` sees. It is only for
+/// reading, even if it is our own copy: `rlu_try_lock` hands out the copy to write.
+/// Null for a null `obj`.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_dereference<T : ClonedT>(g_rlu : * mut RluGlobal, thread_id : usize, obj : *mut Rlu<T>) -> Result<*const T, RluError> {
     debug_log!("Thread {thread_id}: dereference");
     unsafe {
+        let (rlu_global, thread_data) = rlu_thread(g_rlu, thread_id)?;
+        if !thread_data.in_section() {
+            return Err(RluError::NotInSection);
+        }
 
         if obj.is_null() {
-            return ptr::null_mut();
+            return Ok(ptr::null());
         }
 
-        let actual_obj: &mut ObjOriginal<T> = (*obj).deref_mut();
-        let copy = actual_obj.copy.load(Ordering::SeqCst).as_mut();
-        match copy {
-            None => { 
-                debug_log!("return original");
-                &mut actual_obj.data as *mut T
-            }
+        // Only raw pointers from here on: other threads read the same original and
+        // copy at the same time, and the writer of the copy may hold a &mut to it.
+        let actual_obj = (*obj).0;
+        debug_assert_domain(rlu_global, actual_obj);
+        let copy = (*actual_obj).copy.load(Ordering::Acquire); // (4), (5)
+        if copy.is_null() {
+            debug_log!("return original");
+            return Ok(ptr::addr_of!((*actual_obj).body.data));
+        }
 
-            Some(copy) => {
-                let lockthd = copy.thread_id;
-                if thread_id == lockthd {
-                    debug_log!("deref self?");
-                    return &mut copy.data as *mut T;
-                } else {
-                    
-                    let other_write_clock = (*g_rlu).threads[lockthd].write_clock.load(Ordering::SeqCst); // get other write lock
+        let lockthd = (*copy).thread_id.load(Ordering::Relaxed);
+        if thread_id == lockthd {
+            debug_log!("deref self?");
+            return Ok(ptr::addr_of!((*copy).body.data).cast::<T>());
+        }
 
+        let other_write_clock = rlu_global.threads[lockthd].write_clock.load(Ordering::Acquire); // get other write lock (4)
 
-                    let my_local_clock = (*g_rlu).threads[thread_id].write_clock.load(Ordering::SeqCst);// get our own local clock
 
+        let my_local_clock = thread_data.section.local_clock.load(Ordering::Relaxed);// get our own local clock
 
-                    if other_write_clock <= my_local_clock {
-                        debug_log!("deref other copy?");
-                        return &mut copy.data as *mut T;
-                    }
 
-                    debug_log!("deref original");
-                    return &mut actual_obj.data as *mut T;
-                }
-            }
+        // steal the copy only if its writer committed before our section started
+        if other_write_clock <= my_local_clock {
+            debug_log!("deref other copy?");
+            return Ok(ptr::addr_of!((*copy).body.data).cast::<T>());
         }
+
+        debug_log!("deref original");
+        Ok(ptr::addr_of!((*actual_obj).body.data))
     }
 }
 
-pub fn rlu_try_lock<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_id : usize, obj: *mut Rlu<T>) -> Option<*mut T>{
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_try_lock<T : ClonedT>(g_rlu : * mut RluGlobal, thread_id : usize, obj: *mut Rlu<T>) -> Result<*mut T, RluError> {
     debug_log!("Thread {thread_id}: try lock for write");
     unsafe {
-        if !g_rlu.is_null() { // Safety check
-            if thread_id < RLU_MAX_THREADS {
-                let rlu_global = &mut *g_rlu;
-                //let thread_data = &mut rlu_global.threads[thread_id];
-                
-
-                // handle if object is null
-                
-
-
-                rlu_global.threads[thread_id].is_writer = true;
-                let actual_obj = (*obj).deref();
-                // get copy from original;
-                if let Some(ptr_copy) = (*obj).deref_mut().copy.load(Ordering::SeqCst).as_mut() {
-                    // locked
-                    let thr_id = ptr_copy.thread_id;
-                    
-                    if thread_id == thr_id {
-
-                        if rlu_global.threads[thr_id].run_cnt.load(Ordering::SeqCst)  == rlu_global.threads[thread_id].run_cnt.load(Ordering::SeqCst) {
-                            debug_log!("Tried locking from same execution of thread");
-                            return Some(&mut ptr_copy.data as *mut T);
-                        }
-                        
-                        return None;
-                    } 
-                    return None;
-                } 
-                
-                // Append to ptr_copy log
-                let active_log = &mut rlu_global.threads[thread_id].write_log[rlu_global.threads[thread_id].current_log];
-                let curr_idx = active_log.curr_size;
-                //let next_idx = active_log.curr_size + 1;
-                active_log.curr_size += 1;
-                let copy_obj = active_log.log.get_unchecked_mut(curr_idx);
-                copy_obj.thread_id = thread_id;
-                copy_obj.original = *obj;
-                copy_obj.data = actual_obj.data.clone();
-                
-
-
-                let prev = actual_obj.copy.compare_and_swap(ptr::null_mut(), copy_obj, Ordering::SeqCst);
-                if prev != ptr::null_mut() {
-                    // failed
-                    active_log.curr_size -= 1;
-                    return None;
+        let (rlu_global, thread_data) = rlu_thread(g_rlu, thread_id)?;
+        if !thread_data.in_section() || !(*thread_data.owner()).upgraded {
+            // see rlu_upgrade
+            return Err(RluError::NotInSection);
+        }
+
+        if obj.is_null() {
+            return Err(RluError::NullObject);
+        }
+
+        (*thread_data.owner()).is_writer = true;
+        let actual_obj = (*obj).0;
+        debug_assert_domain(rlu_global, actual_obj);
+        // get copy from original;
+        let ptr_copy = (*actual_obj).copy.load(Ordering::Acquire); // (4)
+        if !ptr_copy.is_null() {
+            // locked
+            let thr_id = (*ptr_copy).thread_id.load(Ordering::Relaxed);
+            
+            if thread_id == thr_id {
+
+                if (*ptr_copy).run_cnt.load(Ordering::Relaxed) == thread_data.section.run_cnt.load(Ordering::Relaxed) {
+                    debug_log!("Tried locking from same execution of thread");
+                    return Ok(ptr::addr_of_mut!((*ptr_copy).body.data).cast::<T>());
                 }
 
-                return Some(&mut copy_obj.data as *mut T);
+                // locked by one of our earlier deferred sections: write it back
+                // once this section is aborted, then the retry can lock it again
+                thread_data.requests.sync_request.store(true, Ordering::Relaxed);
+                return Err(RluError::Conflict { owner: thread_id });
+            }
 
-            } else {
-                panic!("Thread ID out of bounds...");
+            if rlu_global.config.mode == RluMode::Deferred {
+                rlu_global.threads[thr_id].requests.sync_request.store(true, Ordering::Relaxed);
+                // in case the owner stays idle, see rlu_help_sync
+                (*thread_data.owner()).blocked_on = Some(thr_id);
             }
+            return Err(RluError::Conflict { owner: thr_id });
+        } 
+        
+        // Append to ptr_copy log
+        let active_log = (*thread_data.owner()).log();
+        if active_log.len() >= rlu_global.config.write_log_capacity {
+            return Err(RluError::LogFull);
         }
-        panic!("Global RLU is null...");
-    }
-}
+        let copy_obj = active_log.reserve(
+            thread_id,
+            thread_data.section.run_cnt.load(Ordering::Relaxed),
+            *obj,
+        );
+        
 
-pub fn rlu_commit_write_log<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_id : usize) {
-    debug_log!("Thread {thread_id}: commit write log");
-    unsafe {
-        if !g_rlu.is_null() { // safety check
 
-            if thread_id < RLU_MAX_THREADS {
-                let rlu_global = &mut *g_rlu;
-                let thread_data = &mut rlu_global.threads[thread_id];
+        let prev = (*actual_obj).copy.compare_exchange(ptr::null_mut(), copy_obj, Ordering::AcqRel, Ordering::Acquire); // (4), (5)
+        if let Err(other) = prev {
+            // failed, the slot stays free
+            let owner = (*other).thread_id.load(Ordering::Relaxed); // (5)
+            return Err(RluError::Conflict { owner });
+        }
 
+        // Only copy the data once the lock is ours, before that the holder
+        // could be writing back to it. Nobody else reads the copy's data
+        // before we commit (2).
+        active_log.push::<T>();
 
-                thread_data.write_clock.store(rlu_global.global_clock.load(Ordering::SeqCst) + 1, Ordering::SeqCst);
-                rlu_global.global_clock.fetch_add(1, Ordering::SeqCst);
-               
+        Ok(ptr::addr_of_mut!((*copy_obj).body.data).cast::<T>())
+    }
+}
 
-            } else {
-                    panic!("Thread ID out of bounds...");
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_commit_write_log(g_rlu : * mut RluGlobal, thread_id : usize) {
+    debug_log!("Thread {thread_id}: commit write log");
+    unsafe {
+        let rlu_global = &*g_rlu;
+        let thread_data = &rlu_global.threads[thread_id];
+
+        // the fence in readers_in_section follows (1), the copies and
+        // write_clock are released to readers that see the new clock (2).
+        // write_clock must be the very clock value we publish: a reader
+        // whose clock is only covered by another writer's increment would
+        // be let go by synchronize without seeing our copies. A reader may
+        // steal on an attempt that lost the race, so each one releases the
+        // copies on its own.
+        let mut clock = rlu_global.global_clock.load(Ordering::Relaxed);
+        loop {
+            thread_data.write_clock.store(clock + 1, Ordering::Release); // (2)
+            match rlu_global.global_clock.compare_exchange_weak(clock, clock + 1, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => clock = current,
             }
-        }   
+        }
     }
 
 
@@ -347,20 +1826,8 @@ pub fn rlu_commit_write_log<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_id :
     rlu_unlock_write_log(g_rlu, thread_id);
 
     unsafe {
-        if !g_rlu.is_null() { // safety check
-
-            if thread_id < RLU_MAX_THREADS {
-
-                let rlu_global = &mut *g_rlu;
-                let thread_data = &mut rlu_global.threads[thread_id];
-                
-                thread_data.write_clock.store(std::u64::MAX as usize, Ordering::SeqCst);
-               
-
-            } else {
-                    panic!("Thread ID out of bounds...");
-            }
-        } 
+        let rlu_global = &*g_rlu;
+        rlu_global.threads[thread_id].write_clock.store(usize::MAX, Ordering::Release); // (4)
     }
     //swap write logs
     rlu_swap_write_logs(g_rlu, thread_id);
@@ -370,97 +1837,115 @@ pub fn rlu_commit_write_log<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_id :
 
 }
 
-pub fn rlu_synchronize<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_id : usize){
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_synchronize(g_rlu : * mut RluGlobal, thread_id : usize){
     debug_log!("Thread {thread_id}: sync");
     unsafe {
-        let rlu_global = &mut *g_rlu;
+        let rlu_global = &*g_rlu;
         let thread = &rlu_global.threads[thread_id];
 
-        let n = rlu_global.n_threads.load(Ordering::SeqCst);
-        let sync_cnts: Vec<usize> = (0..n)
-            .map(|i| rlu_global.threads[i].run_cnt.load(Ordering::SeqCst))
-            .collect();
-
-        for i in 0..n {
-            if i == thread_id {
-                continue;
-            }
+        for (i, sync_cnt) in rlu_global.readers_in_section(Some(thread_id)) {
+            let other: &RluThreadData = &rlu_global.threads[i];
+            // readers that started after our clock bump already see our copies
+            rlu_wait_for_reader(rlu_global.config.wait, other, sync_cnt, || {
+                thread.write_clock.load(Ordering::Relaxed) <= other.section.local_clock.load(Ordering::Acquire) // (3)
+            });
+        }
 
-            let other: &RluThreadData<T> = &rlu_global.threads[i];
-            loop {
-                if sync_cnts[i] % 2 == 0 {
-                    debug_log!("Thread {thread_id} d0");
-                    break;
-                }
+    }
+}
 
-                if other.run_cnt.load(Ordering::SeqCst) != sync_cnts[i] {
-                    debug_log!("Thread {thread_id} d1");
-                    break;
-                }
+// Waits until `other` has left the section it was in when its run_cnt was
+// `sync_cnt`, or until `released` says we don't need to wait for it anymore.
+fn rlu_wait_for_reader(wait : RluWaitStrategy, other : &RluThreadData, sync_cnt : usize, released : impl Fn() -> bool) {
+    let mut rounds : usize = 0;
+    loop {
+        if other.section.run_cnt.load(Ordering::Acquire) != sync_cnt { // (3)
+            break;
+        }
 
-                if thread.write_clock.load(Ordering::SeqCst) <= other.local_clock.load(Ordering::SeqCst) {
-                    debug_log!("Thread {thread_id} d2");
-                    break;
-                }
-            }
-            
+        if released() {
+            break;
         }
 
+        // whatever released looks at can only change with a new section, so from
+        // here on only run_cnt can let us through
+        if rounds < wait.spins {
+            spin_loop();
+        } else if rounds - wait.spins < wait.yields {
+            yield_now();
+        } else if wait.park {
+            other.wait_for_section_end(sync_cnt);
+        } else {
+            spin_loop();
+        }
+        rounds = rounds.saturating_add(1);
     }
 }
 
-pub fn rlu_swap_write_logs<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_id : usize) {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_swap_write_logs(g_rlu : * mut RluGlobal, thread_id : usize) {
     debug_log!("Thread {thread_id}: swap write log");
     unsafe {
-        let rlu_global = &mut *g_rlu;
-        let thread_data = &mut rlu_global.threads[thread_id];
+        let rlu_global = &*g_rlu;
+        let owner = &mut *rlu_global.threads[thread_id].owner();
 
-        thread_data.current_log = (thread_data.current_log + 1)%2;
-        let curr_log: &mut WriteLog<T> = &mut thread_data.write_log[thread_data.current_log];
-        curr_log.curr_size = 0; // start from log beginning, which basically means empty log
+        owner.current_log = (owner.current_log + 1)%2;
+        let curr_log: &mut WriteLog = owner.log();
+        curr_log.truncate(0); // start from log beginning, which basically means empty log
         
         
     }
 }
 
-pub fn rlu_abort<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_id : usize) {
+/// Ends the current section without committing anything it locked or freed. Only the
+/// outermost section can be aborted: nested code hands its error to the outer one.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_abort(g_rlu : * mut RluGlobal, thread_id : usize) -> Result<(), RluError> {
     debug_log!("Thread {thread_id}: abort");
     unsafe {
-        if !g_rlu.is_null() { // safety check
-            // abort when lock failed and we will retry from same thread
-            // basically makes run_cnt even again
-            if thread_id < RLU_MAX_THREADS {
-                let rlu_global = &mut *g_rlu;
-                let thread_data = &mut rlu_global.threads[thread_id];
-                let cnt = thread_data.run_cnt.fetch_add(1, Ordering::SeqCst);
-                assert_ne!((cnt & 0x1), 0);
-                if thread_data.is_writer {
-                    // unlock write log here
-                    thread_data.is_writer = false;
-                    rlu_unlock_write_log(g_rlu, thread_id);
-                }
-                
+        // abort when lock failed and we will retry from same thread
+        // basically makes run_cnt even again
+        let (rlu_global, thread_data) = rlu_thread(g_rlu, thread_id)?;
+        if !thread_data.in_section() {
+            return Err(RluError::NotInSection);
+        }
+        if (*thread_data.owner()).depth > 1 {
+            return Err(RluError::NestedSection);
+        }
+        (*thread_data.owner()).depth = 0;
+
+        thread_data.section.run_cnt.fetch_add(1, Ordering::Release); // (3)
+        thread_data.wake_waiters();
+
+        // whatever the section unlinked is still linked
+        let owner = &mut *thread_data.owner();
+        owner.free_nodes.truncate(owner.section_frees);
+
+        if mem::replace(&mut owner.is_writer, false) {
+            // unlock the copies of this section, deferred ones from earlier
+            // sections stay locked
+            let from = owner.section_copies;
+            rlu_unlock_write_log_from(g_rlu, thread_id, from);
+            // nobody could steal them, we were not committing
+            (*thread_data.owner()).log().truncate(from);
+        }
 
-                // retry code - can be done from specific data str library(?)
-            } else {
-                    panic!("Thread ID out of bounds...");
-            }
-        }   
+        if rlu_global.config.mode == RluMode::Deferred {
+            rlu_maybe_sync(g_rlu, thread_id);
+        }
     }
+    Ok(())
 }
 
-pub fn rlu_writeback_write_log<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_id : usize) {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_writeback_write_log(g_rlu : * mut RluGlobal, thread_id : usize) {
     debug_log!("Thread {thread_id}: writeback write log");
     unsafe {
-        let rlu_global = &mut *g_rlu;
-        let thread_data = &mut rlu_global.threads[thread_id];
-            
-        let curr_log =&mut thread_data.write_log[thread_data.current_log];
-        for i in 0..curr_log.curr_size {
-            let copy = &mut curr_log.log[i];
-            
-            let actual = copy.original.deref_mut();
-            actual.data = copy.data.clone();
+        let rlu_global = &*g_rlu;
+        let curr_log = (*rlu_global.threads[thread_id].owner()).log();
+        for entry in curr_log.entries.iter() {
+            (entry.vtable.writeback)(entry.copy);
         }
         
 
@@ -468,64 +1953,202 @@ pub fn rlu_writeback_write_log<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_i
 
 }
 
-pub fn rlu_unlock_write_log<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_id : usize) {
+/// Unlocks every copy in the write log and empties it. Only valid right after
+/// `rlu_writeback_write_log`, which moved the data of the copies out.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_unlock_write_log(g_rlu : * mut RluGlobal, thread_id : usize) {
+    rlu_unlock_write_log_from(g_rlu, thread_id, 0);
+    unsafe {
+        let rlu_global = &*g_rlu;
+        (*rlu_global.threads[thread_id].owner()).log().forget_all();
+    }
+}
+
+// Unlocks the copies from index `from` on. They stay in the log: the caller either
+// drops them (abort) or forgets them (after writeback).
+fn rlu_unlock_write_log_from(g_rlu : * mut RluGlobal, thread_id : usize, from : usize) {
     debug_log!("Thread {thread_id}: unlock write log");
     unsafe {
-        let rlu_global = &mut *g_rlu;
-        let thread_data = &mut rlu_global.threads[thread_id];
-            
-        let curr_log =&mut thread_data.write_log[thread_data.current_log];
-        for i in 0..curr_log.curr_size {
-            let copy = &mut curr_log.log[i];
-            let actual = copy.original.deref_mut();
-            actual.copy.store(null_mut(), Ordering::SeqCst);
-        
+        let rlu_global = &*g_rlu;
+        let curr_log = (*rlu_global.threads[thread_id].owner()).log();
+        for entry in curr_log.entries[from..].iter() {
+            (entry.vtable.unlock)(entry.copy);
+        }
+    }
+}
+
+/// Commits whatever `thread_id` has pending: deferred copies and frees. Must be called
+/// outside of a section. Does nothing if there is nothing to write back.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_sync_and_writeback(g_rlu : * mut RluGlobal, thread_id : usize) -> Result<(), RluError> {
+    unsafe {
+        let (_, thread_data) = rlu_thread(g_rlu, thread_id)?;
+        if thread_data.in_section() {
+            return Err(RluError::NestedSection);
+        }
+        thread_data.claim();
+        sync_and_writeback(g_rlu, thread_id);
+        thread_data.release();
+    }
+    Ok(())
+}
+
+// rlu_sync_and_writeback for callers that know `thread_id` is outside of a section.
+fn sync_and_writeback(g_rlu : * mut RluGlobal, thread_id : usize) {
+    debug_log!("Thread {thread_id}: sync and writeback");
+    unsafe {
+        let rlu_global = &*g_rlu;
+        let thread_data = &rlu_global.threads[thread_id];
+
+        thread_data.requests.sync_request.store(false, Ordering::Relaxed);
+        if (*thread_data.owner()).has_pending_writes() {
+            rlu_commit_write_log(g_rlu, thread_id);
         }
+    }
+}
+
+// Called at the end of a section in deferred mode: writes back once one of the
+// triggers for it fired, then gives back the claim taken in rlu_reader_lock.
+fn rlu_maybe_sync(g_rlu : * mut RluGlobal, thread_id : usize) {
+    unsafe {
+        let rlu_global = &*g_rlu;
+        let thread_data = &rlu_global.threads[thread_id];
+        let owner = &mut *thread_data.owner();
+
+        if thread_data.requests.sync_request.load(Ordering::Relaxed)
+            || owner.log().len() >= rlu_global.config.deferred_log_limit
+            || owner.free_nodes.len() >= rlu_global.config.free_high_water {
+            sync_and_writeback(g_rlu, thread_id);
+        }
+
+        let blocked_on = (*thread_data.owner()).blocked_on.take();
+        thread_data.release();
+        if let Some(owner_id) = blocked_on {
+            rlu_help_sync(g_rlu, owner_id);
+        }
+    }
+}
 
-        curr_log.curr_size = 0;
+// A lock ran into a deferred copy of `owner_id` and asked it to write back, but the
+// owner only does so when one of its sections ends, which an idle thread never
+// gets to. Out of its own section, the writer does it for the owner unless the owner
+// is in a section right now: then it writes back itself at the end of it.
+fn rlu_help_sync(g_rlu : * mut RluGlobal, owner_id : usize) {
+    unsafe {
+        let rlu_global = &*g_rlu;
+        let thread_data = &rlu_global.threads[owner_id];
+        if !thread_data.try_claim() {
+            return;
+        }
+        // it may have written back, or exited, in the meantime
+        if thread_data.section.active.load(Ordering::Relaxed)
+            && thread_data.requests.sync_request.load(Ordering::Relaxed) {
+            debug_log!("Thread {owner_id}: written back by another thread");
+            sync_and_writeback(g_rlu, owner_id);
+        }
+        thread_data.release();
     }
 }
 
 /* this is just for dropping the objects added to free */
-pub fn rlu_process_free<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_id : usize) {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_process_free(g_rlu : * mut RluGlobal, thread_id : usize) {
     debug_log!("Thread {thread_id}: process free");
     unsafe {
-        let rlu_global = &mut *g_rlu;
-        let thread_data = &mut rlu_global.threads[thread_id];
+        let rlu_global = &*g_rlu;
+        let owner = &mut *rlu_global.threads[thread_id].owner();
+
+        if owner.free_nodes.is_empty() {
+            return;
+        }
+
+        let mut batch = mem::take(&mut owner.free_nodes);
+        if rlu_global.config.track_allocations {
+            let mut live = rlu_global.live.lock().unwrap();
+            for obj in batch.iter() {
+                live.remove(&obj.addr());
+            }
+        }
+
+        if let Some(reclaimer) = rlu_global.reclaimer.lock().unwrap().as_ref() {
+            match reclaimer.batches.send(batch) {
+                Ok(()) => return,
+                // the reclaimer died, drop them here after all
+                Err(mpsc::SendError(rest)) => batch = rest,
+            }
+        }
 
-        for i in 0..thread_data.free_nodes_size {
-            Box::from_raw(thread_data.free_nodes[i].0); //deallocate memory - hack
+        for obj in batch {
+            obj.drop_now(); //deallocate memory - hack
         }
-        thread_data.free_nodes_size = 0;
     }
 }
 
 /* this is for freeing objects*/
-pub fn rlu_free<T : ClonedT>(g_rlu : * mut RluGlobal<T>, thread_id : usize, obj : *mut Rlu<T>) {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn rlu_free<T : ClonedT>(g_rlu : * mut RluGlobal, thread_id : usize, obj : *mut Rlu<T>) -> Result<(), RluError> {
     debug_log!("Thread {thread_id}: free");
 
     unsafe {
-        let rlu_global = &mut *g_rlu;
-        let thread_data = &mut rlu_global.threads[thread_id];
-        
+        let (rlu_global, thread_data) = rlu_thread(g_rlu, thread_id)?;
+        if !thread_data.in_section() {
+            return Err(RluError::NotInSection);
+        }
+
+        if obj.is_null() {
+            return Err(RluError::NullObject);
+        }
 
-        let fid = thread_data.free_nodes_size;
-        thread_data.free_nodes_size += 1;
-        thread_data.free_nodes[fid] = *obj; // not sure if this will work, otherwise we have to pass the mutable pointer
+        debug_assert_domain(rlu_global, (*obj).0);
+        let free_nodes = &mut (*thread_data.owner()).free_nodes;
+        if free_nodes.len() >= rlu_global.config.free_list_capacity {
+            return Err(RluError::FreeListFull);
+        }
+        free_nodes.push(ErasedObj::new(*obj));
     }
+    Ok(())
+}
 
+// An object is only guarded by the locks and clocks of the domain that allocated it.
+unsafe fn debug_assert_domain<T>(rlu_global : &RluGlobal, obj : *mut ObjOriginal<T>) {
+    debug_assert_eq!(
+        (*obj).domain, rlu_global.id,
+        "an object of domain {} was used with domain {}", (*obj).domain, rlu_global.id
+    );
 }
 
 
-// pub fn rlu_assign_ptr<T: ClonedT>(p_ptr: *mut *mut Rlu<T>, obj : *mut Rlu<T>) {
-//     unsafe {
-//         if obj.is_null() {
-//             (*p_ptr) = obj;
-//             return;
-//         }
+/* this is for linking objects, like RLU_ASSIGN_PTR */
+/// Points `p_ptr` at `obj`, or at nothing. Taking `&mut` means `p_ptr` is a field of
+/// a copy locked in the current section, or of an object nobody else can see yet.
+pub fn rlu_assign_ptr<T>(p_ptr : &mut RluPtr<T>, obj : Option<Rlu<T>>) {
+    *p_ptr = RluPtr::from(obj);
+}
 
-//         *p_ptr = (*obj).deref_mut();
+/// Whether `a` and `b` point at the same object, like RLU_IS_SAME_PTRS.
+pub fn rlu_is_same_ptrs<T>(a : &RluPtr<T>, b : &RluPtr<T>) -> bool {
+    a.0 == b.0
+}
 
+/// Whether `a` and `b`, as returned by rlu_dereference or rlu_try_lock, are versions
+/// of the same object: the original, our copy or another writer's copy. Comparing
+/// the pointers themselves says nothing. Null is only the same as null.
+///
+/// # Safety
+///
+/// Each of `a` and `b` must be null or come from rlu_dereference or rlu_try_lock in a
+/// section that is still open: the object is found through a header in front of it.
+pub unsafe fn rlu_same_object<T>(a : *const T, b : *const T) -> bool {
+    if a.is_null() || b.is_null() {
+        return a.is_null() && b.is_null();
+    }
+    rlu_original_of(a) == rlu_original_of(b)
+}
 
-//     }
-// }
\ No newline at end of file
+// The original that `data`, the data of an original or of a copy, belongs to. The
+// header is outside of what a `&T` may access, so it is reached through the
+// provenance exposed when the object or the log chunk was allocated.
+unsafe fn rlu_original_of<T>(data : *const T) -> *mut ObjOriginal<T> {
+    let body = ptr::with_exposed_provenance::<ObjData<T, T>>(data.addr() - mem::offset_of!(ObjData<T, T>, data));
+    (*body).original
+}
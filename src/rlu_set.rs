@@ -1,375 +1,333 @@
 use crate::concurrent_set::ConcurrentSet;
-use crate::rlu::{
-self, Rlu, RluGlobal, RluThreadData
-};
-use crate::{rlu_abort, rlu_dereference, rlu_free, rlu_reader_lock, rlu_reader_unlock, rlu_thread_init, rlu_try_lock};
-use std::fmt::Debug;
-use std::marker::{Unpin, PhantomData};
+use crate::rlu::{ReadSection, Rlu, RluConfig, RluGlobal, RluPtr, RluStats, RluThreadToken};
+use crate::sync::{AtomicBool, Ordering};
+use crate::{rlu_sync_and_writeback, rlu_thread_exit, rlu_thread_init};
+use std::fmt::{self, Debug};
+use std::marker::Unpin;
+use std::sync::Arc;
 use std::mem::MaybeUninit;
-use std::sync::atomic::{AtomicPtr, Ordering};
-use std::{mem, ptr};
 
 
-pub struct RluSet<T : 'static + Clone> {
+// insert and delete have no way to report a failed write
+const NEVER_GIVE_UP: &str = "RluSet needs a contention policy that never gives up";
+// a handle is registered and outside of any section between calls
+const OWN_THREAD: &str = "the thread of an RluSet handle was used through the raw API";
+
+pub struct RluSet<T : 'static + Copy + Send + Sync> {
+  shared: Arc<RluSetShared<T>>,
+  thread_id: Option<usize>, // None with auto_register: looked up on every call
+  in_use: AtomicBool, // a call is working through thread_id
+}
+
+// The thread one call of a handle works through, until it is dropped.
+struct HandleThread<'a> {
+  token: RluThreadToken<'a>,
+  in_use: Option<&'a AtomicBool>, // the handle's own thread, given back on drop
+}
+
+// What all handles of one set share. The last handle to go drops it, and with it
+// the nodes and the RLU domain.
+struct RluSetShared<T : 'static + Copy + Send + Sync> {
   head: Rlu<RluNode<T>>,
-  thread_id: usize,
-  rlu_global: *mut RluGlobal<RluNode<T>>,
+  rlu_global: Arc<RluGlobal>, // shared with the threads registered by current_thread
 }
 
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug)]
 pub struct RluNode<T>{
-  elem: T,
-  next: *mut Rlu<RluNode<T>>,
+  elem: MaybeUninit<T>, // left uninitialized in the head sentinel only
+  next: RluPtr<RluNode<T>>,
 }
 
 
 // In case you need raw pointers in your RluSet, you can assert that RluSet is definitely
 // Send and Sync  
-unsafe impl<T : Clone> Send for RluSet<T> {}
-unsafe impl<T : Clone> Sync for RluSet<T> {}
+unsafe impl<T : Copy + Send + Sync> Send for RluSet<T> {}
+unsafe impl<T : Copy + Send + Sync> Sync for RluSet<T> {}
 
-impl<T> RluSet<T> where T: PartialEq + PartialOrd + Copy + Clone + Debug + Unpin {
+impl<T : Copy> Clone for RluNode<T> {
+  fn clone(&self) -> Self {
+    *self
+  }
+}
+
+impl<T : Copy> Copy for RluNode<T> {}
+
+impl<T : Copy> RluNode<T> {
+  // Never called on the head sentinel.
+  fn elem(&self) -> T {
+    unsafe { self.elem.assume_init() }
+  }
+}
+
+impl<T> RluSet<T> where T: PartialEq + PartialOrd + Copy + Clone + Debug + Unpin + Send + Sync {
   pub fn new() -> RluSet<T> {
+    RluSet::with_config(RluConfig::default())
+  }
 
-    let rlu_global : *mut RluGlobal<RluNode<T>> = RluGlobal::init();
-    let rlu_global_obj = unsafe { & *rlu_global };
-    let thread_id = rlu_thread_init(rlu_global);
+  /// `config.contention` must keep retrying, and the capacities of `config` must fit
+  /// one insert or delete: insert and delete panic otherwise. With
+  /// `config.auto_register` a single handle can be shared by all threads, through an
+  /// `Arc` for example, and `clone_ref` is not needed. Without it, each handle has
+  /// its own thread. A handle shared anyway serves one call at a time through it,
+  /// and calls that overlap go through their thread's own registration instead, as
+  /// with `auto_register`.
+  pub fn with_config(config: RluConfig) -> RluSet<T> {
+
+    let rlu_global = Arc::new(RluGlobal::new(config));
+    let head = rlu_global.alloc(
+      RluNode {
+        elem: MaybeUninit::uninit(),
+        next: RluPtr::null(),
+      }
+    );
+    let shared = Arc::new(RluSetShared { head, rlu_global });
 
     RluSet{
-      head: rlu_global_obj.alloc(
-        RluNode {
-          elem: unsafe{ mem::uninitialized()},
-          next: ptr::null_mut(),
-        }
-      ),
-      thread_id: thread_id,
-      rlu_global: rlu_global,
+      thread_id: shared.register(),
+      in_use: AtomicBool::new(false),
+      shared,
     }
   }
 
-  pub fn to_string(&self) -> String {
-    //unimplemented!()
-    let mut ret = String::from("{");
-    unsafe {
-
-        let mut node_ptr = &self.head as *const Rlu<RluNode<T>> as *mut Rlu<RluNode<T>>;
-
-        let mut node = rlu_dereference(self.rlu_global, self.thread_id, node_ptr);
+  /// In deferred mode, publishes the updates this handle has not written back yet.
+  /// Other handles keep seeing the set without them until then.
+  pub fn sync(&self) {
+    let thread = self.thread();
+    rlu_sync_and_writeback(self.global().as_ptr(), thread.token.thread_id()).expect(OWN_THREAD);
+  }
 
-        node_ptr = (*node).next;
+  /// Drops deleted nodes on a background thread instead of in `delete`.
+  /// See `RluGlobal::start_reclaimer`.
+  pub fn start_reclaimer(&self) {
+    self.global().start_reclaimer();
+  }
 
-        rlu_reader_lock(self.rlu_global, self.thread_id);
-        loop {
-            if node_ptr.is_null() {
-                break;
-            } else {
+  /// Stops the background thread once it dropped every node it was handed.
+  pub fn stop_reclaimer(&self) {
+    self.global().stop_reclaimer();
+  }
 
-                let tmp = rlu_dereference(self.rlu_global, self.thread_id, node_ptr);
+  /// Conflict counters of the set, shared by all handles.
+  pub fn stats(&self) -> RluStats {
+    self.global().stats()
+  }
 
-                ret.push_str(&format!("{:?}, ", (*tmp).elem));
+  fn global(&self) -> &RluGlobal {
+    &self.shared.rlu_global
+  }
 
-                node_ptr = (*tmp).next;
+  fn read<'a>(&'a self, thread: &HandleThread<'a>) -> ReadSection<'a> {
+    self.global().read(thread.token).expect(OWN_THREAD)
+  }
 
-                println!("{}", ret);
-                
-            }
-        }
-        rlu_reader_unlock(self.rlu_global, self.thread_id);
+  fn thread(&self) -> HandleThread<'_> {
+    match self.thread_id {
+      // registered until the handle is dropped, and in_use keeps the other threads
+      // sharing this handle off it until the call is done
+      Some(thread_id) if self.in_use.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() => {
+        HandleThread { token: unsafe { self.global().token(thread_id) }, in_use: Some(&self.in_use) }
+      }
+      // auto_register, or another thread is in a call of this handle
+      _ => HandleThread { token: RluGlobal::current_thread(&self.shared.rlu_global), in_use: None },
     }
-    ret.push('}');
-    ret
   }
-
 }
 
-
-impl<T> ConcurrentSet<T> for RluSet<T> where T: PartialEq + PartialOrd + Copy + Clone + Debug + Unpin {
-  
-  fn contains(&self, value: T) -> bool {
-    let mut ret = false;
-
-    rlu_reader_lock(self.rlu_global, self.thread_id);
-
-    let mut node_ptr = &self.head as *const Rlu<RluNode<T>> as *mut Rlu<RluNode<T>>;
-
-    let mut node = rlu_dereference(self.rlu_global, self.thread_id, node_ptr);
-
-
-    let mut first_deref = true;
-
-    loop {
-          if node_ptr.is_null() {
-            break;
-          }
-          else if first_deref {
-
-            first_deref = false;
-
-            let next_ptr = unsafe{ (*node).next }; 
-
-            if next_ptr.is_null() {
-              break;
-            }
-
-            node_ptr = next_ptr;
-
-            continue;
-
-          } else {
-
-            node = rlu_dereference(self.rlu_global, self.thread_id, node_ptr);
-
-            let v = unsafe{ (*node).elem};
-
-            if v > value {
-              break;
-            }
-
-            if v == value {
-              ret = true;
-              break;
-            }
-            
-            let next_ptr = unsafe { (*node).next };
-            // increment ptr
-            node_ptr = next_ptr;
-            
-          }
+impl Drop for HandleThread<'_> {
+  fn drop(&mut self) {
+    if let Some(in_use) = self.in_use {
+      in_use.store(false, Ordering::Release);
     }
-
-    rlu_reader_unlock(self.rlu_global, self.thread_id);
-    ret
   }
+}
 
+impl<T> Default for RluSet<T> where T: PartialEq + PartialOrd + Copy + Clone + Debug + Unpin + Send + Sync {
+  fn default() -> RluSet<T> {
+    RluSet::new()
+  }
+}
 
+impl<T : Copy + Send + Sync> Drop for RluSet<T> {
+  fn drop(&mut self) {
+    // give the thread slot back so clone_ref can be called any number of times
+    if let Some(thread_id) = self.thread_id {
+      rlu_thread_exit(self.shared.rlu_global.as_ptr(), thread_id).expect(OWN_THREAD);
+    }
+  }
+}
 
-  fn len(&self) -> usize {
-      let mut len = 0;
-
-      rlu_reader_lock(self.rlu_global, self.thread_id);
-
-      let mut node_ptr = &self.head as *const Rlu<RluNode<T>> as *mut Rlu<RluNode<T>>;
-
-      let mut node = rlu_dereference(self.rlu_global, self.thread_id, node_ptr);
-
-      let mut first_deref = true;
-
-
-      loop {
-        if node_ptr.is_null() {
-          break;
-        } else {
-          if !first_deref {
-            len += 1;
-          } else {
-            first_deref = false;
-          }
-
-          node = rlu_dereference(self.rlu_global, self.thread_id, node_ptr);
+impl<T : Copy + Send + Sync> RluSetShared<T> {
+  // A thread for a new handle, unless threads register themselves.
+  fn register(&self) -> Option<usize> {
+    if self.rlu_global.config().auto_register {
+      None
+    } else {
+      Some(rlu_thread_init(self.rlu_global.as_ptr()))
+    }
+  }
+}
 
-          // increment ptr
-          node_ptr = unsafe{ (*node).next };
+impl<T : Copy + Send + Sync> Drop for RluSetShared<T> {
+  fn drop(&mut self) {
+    // every handle is gone, nobody else can be looking at the nodes. Threads that
+    // registered through current_thread may still hold deferred copies of them.
+    self.rlu_global.sync_all();
+    let mut thread = self.rlu_global.register();
+    let mut next = Some(self.head);
+    while next.is_some() {
+      let mut section = thread.read().upgrade();
+      while let Some(node) = next {
+        let after = section.dereference(&node).next.load(&section);
+        // only this walk still reaches the nodes, and it visits each one once
+        if unsafe { section.free(node) }.is_err() {
+          break; // the free list is full, commit it and go on in a new section
         }
+        next = after;
       }
-
-      rlu_reader_unlock(self.rlu_global, self.thread_id);
-
-      len
+      drop(section);
+      thread.sync_and_writeback();
     }
+  }
+}
 
-  fn insert(&self, value: T) -> bool {
-    let rlu_global_obj = unsafe { &*self.rlu_global };
-
-    loop {
-        rlu_reader_lock(self.rlu_global, self.thread_id);
-
-        let mut prev_ptr = &self.head as *const Rlu<RluNode<T>> as *mut Rlu<RluNode<T>>;
-
-        let mut prev = rlu_dereference(self.rlu_global, self.thread_id, prev_ptr); // ptr to list head
-
-
-        let mut next_ptr = unsafe { (*prev).next };
-
-        let mut next = rlu_dereference(self.rlu_global, self.thread_id, next_ptr); // ptr to next ptr to prev
-
-        let mut matches = false;
-
-        loop {
-            if next_ptr.is_null() {
-                break;
-            }
-
-            let v = unsafe { (*next).elem };
-
-            if v >= value {
-                if v == value {
-                    matches = true;
-                }
-                break;
-            }
-            prev_ptr = next_ptr;       // prev ptr now points to next ptr          
-
-            prev = rlu_dereference(self.rlu_global, self.thread_id, prev_ptr);
-
-            next_ptr = unsafe { (*prev).next };
+impl<T> fmt::Display for RluSet<T> where T: PartialEq + PartialOrd + Copy + Clone + Debug + Unpin + Send + Sync {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let thread = self.thread();
+    let section = self.read(&thread);
+
+    write!(f, "{{")?;
+    let mut next = section.dereference(&self.shared.head).next.load(&section);
+    while let Some(node) = next {
+      let node = section.dereference(&node);
+      write!(f, "{:?}, ", node.elem())?;
+      next = node.next.load(&section);
+    }
+    write!(f, "}}")
+  }
+}
 
-            next = rlu_dereference(self.rlu_global, self.thread_id, next_ptr);
-        }
 
-        if matches {
-            break;
-        }
+impl<T> ConcurrentSet<T> for RluSet<T> where T: PartialEq + PartialOrd + Copy + Clone + Debug + Unpin + Send + Sync {
+  
+  fn contains(&self, value: T) -> bool {
+    let thread = self.thread();
+    let section = self.read(&thread);
 
+    // skip the sentinel head
+    let mut next = section.dereference(&self.shared.head).next.load(&section);
+    while let Some(node) = next {
+      let node = section.dereference(&node);
 
-        
-        let tmp = rlu_global_obj.alloc(
-          RluNode { 
-            elem: value,
-            next: ptr::null_mut()
-          }
-        );
-
-        // create node
-        let new_node_ptr = Box::into_raw(Box::new(tmp));
+      if node.elem() >= value {
+        return node.elem() == value;
+      }
 
+      next = node.next.load(&section);
+    }
 
-        let prev_ptr_locked = rlu_try_lock(self.rlu_global, self.thread_id, prev_ptr);
+    false
+  }
 
 
 
+  fn len(&self) -> usize {
+    let thread = self.thread();
+    let section = self.read(&thread);
+
+    let mut len = 0;
+    let mut next = section.dereference(&self.shared.head).next.load(&section);
+    while let Some(node) = next {
+      len += 1;
+      next = section.dereference(&node).next.load(&section);
+    }
 
+    len
+  }
 
+  fn insert(&self, value: T) -> bool {
+    let rlu_global_obj = self.global();
+    let thread = self.thread();
 
-        if prev_ptr_locked.is_none() {
-            rlu_abort(self.rlu_global, self.thread_id);
-            continue;
-        } 
+    rlu_global_obj.write(thread.token, |tx| {
+      let mut prev = self.shared.head;
+      let mut next = tx.dereference(&self.shared.head).next.load(tx);
 
-        if !next_ptr.is_null() { // only if inserting to last 
-          let next_ptr_locked = rlu_try_lock(self.rlu_global, self.thread_id, next_ptr);
+      while let Some(node) = next {
+        let node_ref = tx.dereference(&node);
 
-          if next_ptr_locked.is_none() {
-            rlu_abort(self.rlu_global, self.thread_id);
-            continue;
+        if node_ref.elem() >= value {
+          if node_ref.elem() == value {
+            return Ok(false);
           }
+          break;
         }
 
+        prev = node;
+        next = node_ref.next.load(tx);
+      }
 
-        let plocked = prev_ptr_locked.unwrap();
-        // let mut nlocked = next_ptr_locked.unwrap();
-
-        unsafe {
-            let new_locked = rlu_try_lock(self.rlu_global, self.thread_id, new_node_ptr).unwrap();
-
-            (*new_locked).next = next_ptr;
-
-            (*plocked).next = new_node_ptr;
-
-
-            //println!("prev, {:?}", (*plocked));
-            //println!("new , {:?}", (*new_locked));
-
+      tx.lock(&prev)?;
+      if let Some(next) = &next { // only if not inserting at the end
+        tx.lock(next)?;
+      }
 
+      // the new node is only reachable once prev is written back, no need to lock it
+      let new_node = rlu_global_obj.alloc(
+        RluNode {
+          elem: MaybeUninit::new(value),
+          next: next.into(),
         }
+      );
 
-        break;
-    }
-
-    rlu_reader_unlock(self.rlu_global, self.thread_id);
+      // locking again hands back the copy we already hold
+      tx.lock(&prev)?.next.assign(Some(new_node));
 
-    true
-}
+      Ok(true)
+    }).expect(NEVER_GIVE_UP)
+  }
 
 
   fn delete(&self, value: T) -> bool {
-    
-    let mut ret = false;
-
-    'outer : loop {
-
-
-      rlu_reader_lock(self.rlu_global, self.thread_id);
-
-      let mut prev_ptr = &self.head as *const Rlu<RluNode<T>> as *mut Rlu<RluNode<T>>;
-
-      let mut prev = rlu_dereference(self.rlu_global, self.thread_id, prev_ptr); // ptr to list head
-
-
-      let mut next_ptr = unsafe { (*prev).next };
-
-      let mut next = rlu_dereference(self.rlu_global, self.thread_id, next_ptr); // ptr to next ptr to prev
-
-      'inner  :loop {
-        if next_ptr.is_null() {
-          break 'inner;
-        } else {
-          let curr = rlu_dereference(self.rlu_global, self.thread_id, next_ptr);
-
-          let v = unsafe { (*curr).elem };
-
-          if v > value {
-            break 'inner;
-          }
-
-          if v == value {
-            //delete
-
-            let prev_ptr_locked = rlu_try_lock(self.rlu_global, self.thread_id, prev_ptr);
+    let thread = self.thread();
+    self.global().write(thread.token, |tx| {
+      let mut prev = self.shared.head;
+      let mut curr = tx.dereference(&self.shared.head).next.load(tx);
 
-            let next_ptr_locked = rlu_try_lock(self.rlu_global, self.thread_id, next_ptr);
+      while let Some(node) = curr {
+        let node_ref = tx.version(&node);
 
-            if prev_ptr_locked.is_none() || next_ptr_locked.is_none() {
-              rlu_abort(self.rlu_global, self.thread_id);
-              continue 'outer;
-            }
-
-
-            ret = true;
-
-            let plocked = prev_ptr_locked.unwrap();
-
-            let nlocked = next_ptr_locked.unwrap();
-
-            unsafe {
-                
-            (*plocked).next = (*nlocked).next;
-            }
-
-
-            unsafe {
-              rlu_free(self.rlu_global, self.thread_id, next_ptr);
-            }
-
-            break;
-
-          }
-
-
-          prev_ptr = next_ptr;
+        if node_ref.elem() > value {
+          break;
+        }
 
-          prev = rlu_dereference(self.rlu_global, self.thread_id, prev_ptr);
+        if node_ref.elem() == value {
+          // prev, whichever version of it we see, still links to this node
+          debug_assert!(tx.dereference(&prev).next.load(tx)
+            .is_some_and(|linked| tx.same_object(tx.version(&linked), node_ref)));
+          tx.lock(&prev)?;
+          let after = tx.lock(&node)?.next;
 
-          next_ptr = unsafe{ (*prev).next};
+          tx.lock(&prev)?.next = after;
+          // unlinked above, and locked by us, so no other delete frees it too
+          unsafe { tx.free(node)? };
 
-          //next = rlu_dereference(self.rlu_global, self.thread_id, prev_ptr);
+          return Ok(true);
         }
-      }
-
-      rlu_reader_unlock(self.rlu_global, self.thread_id);
-      break;
 
-    }
+        prev = node;
+        curr = node_ref.next.load(tx);
+      }
 
-    ret
+      Ok(false)
+    }).expect(NEVER_GIVE_UP)
   }
 
   fn clone_ref(&self) -> Self {
-    let thread_id = rlu_thread_init(self.rlu_global);
     RluSet { 
-      head: self.head, 
-      thread_id: thread_id, 
-      rlu_global: self.rlu_global 
+      shared: self.shared.clone(), 
+      thread_id: self.shared.register(), 
+      in_use: AtomicBool::new(false),
     }
   }
 }
\ No newline at end of file
@@ -0,0 +1,17 @@
+// What rlu.rs synchronizes with. The `loom` feature swaps in loom's versions so the
+// protocol can be model checked (see tests/loom.rs); the reclaimer thread and the
+// contention policies are not modelled.
+
+#[cfg(not(feature = "loom"))]
+pub(crate) use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+#[cfg(not(feature = "loom"))]
+pub(crate) use std::sync::{Condvar, Mutex};
+#[cfg(not(feature = "loom"))]
+pub(crate) use std::{hint::spin_loop, thread::yield_now};
+
+#[cfg(feature = "loom")]
+pub(crate) use loom::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+#[cfg(feature = "loom")]
+pub(crate) use loom::sync::{Condvar, Mutex};
+#[cfg(feature = "loom")]
+pub(crate) use loom::{hint::spin_loop, thread::yield_now};
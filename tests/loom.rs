@@ -0,0 +1,214 @@
+// Model checks of the RLU protocol. Only built with the loom feature:
+//   cargo test --release --features loom --test loom
+// LOOM_MAX_PREEMPTIONS overrides the default preemption bound of 3, LOOM_MAX_BRANCHES
+// raises the branch limit above 5000.
+#![cfg(feature = "loom")]
+
+use loom::sync::atomic::{AtomicBool, Ordering};
+use loom::sync::Arc;
+use loom::thread;
+use rlu::{ContentionPolicy, Rlu, RluConfig, RluGlobal, RluMode, RluWaitStrategy};
+
+// Retrying without yielding to loom would spin through the whole branch budget.
+#[derive(Debug)]
+struct LoomYield;
+
+impl ContentionPolicy for LoomYield {
+  fn on_conflict(&self, _attempts: usize) -> bool {
+    thread::yield_now();
+    true
+  }
+}
+
+fn config() -> RluConfig {
+  RluConfig {
+    contention: std::sync::Arc::new(LoomYield),
+    // every spin is another interleaving to explore, park right away
+    wait: RluWaitStrategy { spins: 0, yields: 0, park: true },
+    ..RluConfig::default()
+  }
+}
+
+fn model<F>(f: F)
+where
+  F: Fn() + Sync + Send + 'static,
+{
+  let mut builder = loom::model::Builder::new();
+  if builder.preemption_bound.is_none() {
+    builder.preemption_bound = Some(3);
+  }
+  // a writer retrying against a preempted lock holder goes through a section on every
+  // attempt, which takes more than the default 1000 branches
+  builder.max_branches = builder.max_branches.max(5_000);
+  builder.check(f);
+}
+
+#[test]
+fn loom_reader_sees_whole_commit() {
+  model(|| {
+    let rlu_global = Arc::new(RluGlobal::new(config()));
+    let a = rlu_global.alloc(0u64);
+    let b = rlu_global.alloc(0u64);
+
+    let writer = {
+      let rlu_global = rlu_global.clone();
+      thread::spawn(move || {
+        let mut thread = rlu_global.register();
+        thread.write(|tx| {
+          *tx.lock(&a)? = 1;
+          *tx.lock(&b)? = 1;
+          Ok(())
+        }).unwrap();
+      })
+    };
+
+    {
+      let mut thread = rlu_global.register();
+      let section = thread.read();
+      let seen_a = *section.dereference(&a);
+      let seen_b = *section.dereference(&b);
+      assert_eq!(seen_a, seen_b);
+    }
+
+    writer.join().unwrap();
+
+    let mut thread = rlu_global.register();
+    let mut section = thread.read().upgrade();
+    assert_eq!((*section.dereference(&a), *section.dereference(&b)), (1, 1));
+    unsafe { section.free(a) }.unwrap();
+    unsafe { section.free(b) }.unwrap();
+  });
+}
+
+#[test]
+fn loom_abort_is_never_seen() {
+  model(|| {
+    let rlu_global = Arc::new(RluGlobal::new(config()));
+    let obj = rlu_global.alloc(0u64);
+
+    let aborter = {
+      let rlu_global = rlu_global.clone();
+      thread::spawn(move || {
+        let mut thread = rlu_global.register();
+        let mut section = thread.read().upgrade();
+        if let Ok(val) = section.lock(&obj) {
+          *val = 100;
+        }
+        section.abort();
+      })
+    };
+
+    let incrementer = {
+      let rlu_global = rlu_global.clone();
+      thread::spawn(move || {
+        let mut thread = rlu_global.register();
+        thread.write(|tx| {
+          *tx.lock(&obj)? += 1;
+          Ok(())
+        }).unwrap();
+      })
+    };
+
+    {
+      let mut thread = rlu_global.register();
+      let val = *thread.read().dereference(&obj);
+      assert!(val <= 1);
+    }
+
+    aborter.join().unwrap();
+    incrementer.join().unwrap();
+
+    let mut thread = rlu_global.register();
+    let mut section = thread.read().upgrade();
+    assert_eq!(*section.dereference(&obj), 1);
+    unsafe { section.free(obj) }.unwrap();
+  });
+}
+
+// A list link whose drop is observable. Only the unlinked node carries a flag, the
+// copies of the head made by lock don't.
+#[derive(Clone)]
+struct Link {
+  next: Option<Rlu<Link>>,
+  dropped: Option<Arc<AtomicBool>>,
+}
+
+impl Drop for Link {
+  fn drop(&mut self) {
+    if let Some(dropped) = &self.dropped {
+      dropped.store(true, Ordering::SeqCst);
+    }
+  }
+}
+
+#[test]
+fn loom_free_waits_for_readers() {
+  model(|| {
+    let rlu_global = Arc::new(RluGlobal::new(config()));
+    let dropped = Arc::new(AtomicBool::new(false));
+    let node = rlu_global.alloc(Link { next: None, dropped: Some(dropped.clone()) });
+    let head = rlu_global.alloc(Link { next: Some(node), dropped: None });
+
+    let writer = {
+      let rlu_global = rlu_global.clone();
+      thread::spawn(move || {
+        let mut thread = rlu_global.register();
+        thread.write(|tx| {
+          let node = tx.lock(&head)?.next.take().unwrap();
+          unsafe { tx.free(node) }.unwrap();
+          Ok(())
+        }).unwrap();
+      })
+    };
+
+    {
+      let mut thread = rlu_global.register();
+      let section = thread.read();
+      if let Some(node) = section.dereference(&head).next {
+        // still reachable from our section, so it must still be alive
+        assert!(section.dereference(&node).next.is_none());
+        assert!(!dropped.load(Ordering::SeqCst));
+      }
+    }
+
+    writer.join().unwrap();
+    assert!(dropped.load(Ordering::SeqCst));
+
+    let mut thread = rlu_global.register();
+    unsafe { thread.read().upgrade().free(head) }.unwrap();
+  });
+}
+
+#[test]
+fn loom_deferred_writeback_for_idle_owner() {
+  model(|| {
+    let rlu_global = Arc::new(RluGlobal::new(RluConfig { mode: RluMode::Deferred, ..config() }));
+    let obj = rlu_global.alloc(0u64);
+    let mut owner = rlu_global.register();
+    owner.write(|tx| {
+      *tx.lock(&obj)? += 1;
+      Ok(())
+    }).unwrap();
+
+    // writes the owner's copy back for it, unless the owner is in its section
+    let writer = {
+      let rlu_global = rlu_global.clone();
+      thread::spawn(move || {
+        let mut thread = rlu_global.register();
+        thread.write(|tx| {
+          *tx.lock(&obj)? += 1;
+          Ok(())
+        }).unwrap();
+      })
+    };
+
+    // the owner's increment is never lost, the writer's shows once it exits
+    let val = *owner.read().dereference(&obj);
+    assert!(val == 1 || val == 2);
+
+    writer.join().unwrap();
+    let mut section = owner.read().upgrade();
+    assert_eq!(*section.dereference(&obj), 2);
+    unsafe { section.free(obj) }.unwrap();
+  });
+}
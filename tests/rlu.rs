@@ -1,31 +1,42 @@
 #![allow(dead_code, unused_variables)]
 
-use std::{thread, time};
-use rand::Rng;
-use test_log::test;
+use std::{ptr, thread, time};
 
 use rlu::{
   rlu_dereference, rlu_reader_lock, rlu_reader_unlock,
-  rlu_try_lock, rlu_thread_init, rlu_abort, RluGlobal, Rlu
+  rlu_try_lock, rlu_upgrade, rlu_section_depth, rlu_thread_init, rlu_thread_exit, rlu_abort, rlu_free, rlu_same_object, RluConfig, RluGlobal, RluMode, RluThread, Rlu,
+  RluError, RluPtr, RluStats, RluThreadToken, RluWaitStrategy, ContentionPolicy, ImmediateRetry, ExponentialBackoff, YieldRetry, BoundedRetries
 };
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 
 
 #[derive(Copy, Clone, Debug)]
 pub struct RluInt64Wrapper {
   pub obj : *mut Rlu<u64>,
-  pub rlu_global : *mut RluGlobal<u64>
+  pub rlu_global : *mut RluGlobal
 }
 
 
 unsafe impl Send for RluInt64Wrapper {}
 unsafe impl Sync for RluInt64Wrapper {}
 
+// Domains in these tests drop whatever objects are left when they go away.
+fn tracked(config: RluConfig) -> RluConfig {
+  RluConfig { track_allocations: true, ..config }
+}
+
+// Miri is orders of magnitude slower, so the longer tests do fewer rounds under it.
+fn rounds(n: usize) -> usize {
+  if cfg!(miri) { n.div_ceil(10) } else { n }
+}
+
 
 #[test_log::test]
 fn rlu_basic_spawn_threads() {
   /* Put your RLU tests here! Or add more functions below. */
-  let rlu_global: *mut RluGlobal<u64> = RluGlobal::init();
+  let rlu_global: *mut RluGlobal = RluGlobal::init();
   let rlu_global_obj = unsafe { &*rlu_global };
 
   let id = rlu_thread_init(rlu_global);
@@ -42,18 +53,18 @@ fn rlu_basic_spawn_threads() {
   assert_eq!(id1, 1);
   assert_eq!(id2, 2);
 
-  
+  unsafe { RluGlobal::destroy(rlu_global) };
 }
 
 #[test_log::test]
 fn rlu_multiple_threads_read_only() {
-  let rlu_global : *mut RluGlobal<u64> = RluGlobal::init();
+  let rlu_global : *mut RluGlobal = RluGlobal::init_with_config(tracked(RluConfig::default()));
   let rlu_global_obj = unsafe { & *rlu_global };
 
   let test_val = 2;
   let wrapped_int64_obj = RluInt64Wrapper { // need wrapper for unsafe send and sync
     obj : Box::into_raw(Box::new(rlu_global_obj.alloc(test_val))),
-    rlu_global: rlu_global,
+    rlu_global,
   };
 
 
@@ -65,13 +76,13 @@ fn rlu_multiple_threads_read_only() {
     let id1 = rlu_thread_init(rglobal);
     println!("Spawned Reader RLU thread: {id1}");
 
-    rlu_reader_lock(rglobal, id1);
+    rlu_reader_lock(rglobal, id1).unwrap();
 
     thread::sleep(time::Duration::from_millis(100));
 
-    let after  = rlu_dereference(rglobal, id1, obj);
+    let after  = rlu_dereference(rglobal, id1, obj).unwrap();
     assert_eq!(test_val, *after);
-    rlu_reader_unlock(rglobal, id1);
+    rlu_reader_unlock(rglobal, id1).unwrap();
 
   });
 
@@ -84,29 +95,33 @@ fn rlu_multiple_threads_read_only() {
     let id2 = rlu_thread_init(rglobal);
 
     println!("Spawned RLU thread: {id2}");
-    rlu_reader_lock(rglobal, id2);
+    rlu_reader_lock(rglobal, id2).unwrap();
     thread::sleep(time::Duration::from_millis(100));
 
-    let after  = rlu_dereference(rglobal, id2, obj);
+    let after  = rlu_dereference(rglobal, id2, obj).unwrap();
     assert_eq!(test_val, *after);
-    rlu_reader_unlock(rglobal, id2);
+    rlu_reader_unlock(rglobal, id2).unwrap();
 
   });
 
   reader1.join().unwrap();
   reader2.join().unwrap();
-  
+
+  unsafe {
+    drop(Box::from_raw(wrapped_int64_obj.obj));
+    RluGlobal::destroy(rlu_global);
+  }
 } 
 
 
 #[test_log::test]
 fn rlu_single_read_single_writer() {
-  let rlu_global : *mut RluGlobal<u64> = RluGlobal::init();
+  let rlu_global : *mut RluGlobal = RluGlobal::init_with_config(tracked(RluConfig::default()));
   let rlu_global_obj = unsafe { & *rlu_global };
 
   let wrapped_int64_obj = RluInt64Wrapper { // need wrapper for unsafe send and sync
     obj : Box::into_raw(Box::new(rlu_global_obj.alloc(2))),
-    rlu_global: rlu_global,
+    rlu_global,
   };
 
 
@@ -119,19 +134,19 @@ fn rlu_single_read_single_writer() {
       println!("Spawned Reader RLU thread: {id1}");
       
       /* will hold lock value will not change */
-      rlu_reader_lock(rglobal, id1);
-      let val = rlu_dereference(rglobal, id1, obj);
+      rlu_reader_lock(rglobal, id1).unwrap();
+      let val = rlu_dereference(rglobal, id1, obj).unwrap();
       let before = *val;
       thread::sleep(time::Duration::from_millis(200));
       assert_eq!(*val, before);
-      rlu_reader_unlock(rglobal, id1);
+      rlu_reader_unlock(rglobal, id1).unwrap();
 
 
       /* value will change because it happens after 200 millis */
-      rlu_reader_lock(rglobal, id1);
-      let obj2 = rlu_dereference(rglobal, id1, obj);
+      rlu_reader_lock(rglobal, id1).unwrap();
+      let obj2 = rlu_dereference(rglobal, id1, obj).unwrap();
       assert_eq!(*obj2, 3);
-      rlu_reader_unlock(rglobal, id1);
+      rlu_reader_unlock(rglobal, id1).unwrap();
     
 
   });
@@ -170,35 +185,41 @@ fn rlu_single_read_single_writer() {
       let id2 = rlu_thread_init(rglobal);
       println!("Spawned Writer RLU thread: {id2}");
   
-      rlu_reader_lock(rglobal, id2);
+      rlu_reader_lock(rglobal, id2).unwrap();
   
-      let obj2 = rlu_dereference(rglobal, id2, obj);
+      let obj2 = rlu_dereference(rglobal, id2, obj).unwrap();
   
       assert_eq!(*obj2, 2);
 
+      rlu_upgrade(rglobal, id2).unwrap();
       let obj3 = rlu_try_lock(rglobal, id2, obj).unwrap();
       //assert!(rlu_try_lock(rglobal, id2, obj2)); // Fix: pass obj2
       
       // TODO: this is not modifying: fix required in rlu_dereference
       *obj3 += 1;
   
-      rlu_reader_unlock(rglobal, id2);
+      rlu_reader_unlock(rglobal, id2).unwrap();
   });
 
   reader.join().unwrap();
   writer.join().unwrap();
-  
+
+  unsafe {
+    drop(Box::from_raw(wrapped_int64_obj.obj));
+    RluGlobal::destroy(rlu_global);
+  }
 } 
 
 #[test_log::test]
 fn rlu_hold_locks() {
-  let rlu_global : *mut RluGlobal<u64> = RluGlobal::init();
+  let rlu_global : *mut RluGlobal = RluGlobal::init_with_config(tracked(RluConfig::default()));
   let rlu_global_obj = unsafe { & *rlu_global };
 
   let wrapped_int64_obj = RluInt64Wrapper { // need wrapper for unsafe send and sync
     obj : Box::into_raw(Box::new(rlu_global_obj.alloc(0))),
-    rlu_global: rlu_global,
+    rlu_global,
   };
+  let num_increments = rounds(1000) as u64;
 
 
   let reader = |x : u64| {
@@ -211,17 +232,17 @@ fn rlu_hold_locks() {
       let id1 = rlu_thread_init(rglobal);
       println!("Spawned Reader RLU thread: {id1}");
 
-      for _ in 0..100{
+      for _ in 0..rounds(100) {
   
-        rlu_reader_lock(rglobal, id1);
+        rlu_reader_lock(rglobal, id1).unwrap();
     
-        let val = rlu_dereference(rglobal, id1, obj);
+        let val = rlu_dereference(rglobal, id1, obj).unwrap();
         let before = *val;
         thread::sleep(time::Duration::from_millis(10));
     
         assert_eq!(before, *val);
 
-        rlu_reader_unlock(rglobal, id1);
+        rlu_reader_unlock(rglobal, id1).unwrap();
       }
 
 
@@ -239,26 +260,27 @@ fn rlu_hold_locks() {
       let id = rlu_thread_init(rglobal);
       println!("Spawned Writer RLU thread: {id}");
       
-      for i in 0..1000 {
+      for i in 0..num_increments {
 
         loop {
-          rlu_reader_lock(rglobal, id);
+          rlu_reader_lock(rglobal, id).unwrap();
+          rlu_upgrade(rglobal, id).unwrap();
           let wobj = rlu_try_lock(rglobal, id, obj);
 
           match wobj {
-            None => {
-              rlu_abort(rglobal, id);
+            Err(_) => {
+              rlu_abort(rglobal, id).unwrap();
               continue;
             }
 
-            Some(wobj) => {
+            Ok(wobj) => {
               *wobj += 1;
               break;
             }
           }
         }
 
-        rlu_reader_unlock(rglobal, id);
+        rlu_reader_unlock(rglobal, id).unwrap();
         
       }
 
@@ -270,8 +292,8 @@ fn rlu_hold_locks() {
   let num_readers = 16;
   let num_writers = 2;
 
-  let readers: Vec<_> = (0..num_readers).map(|i| reader(i)).collect();
-  let writers: Vec<_> = (0..num_writers).map(|i| writer(i)).collect();
+  let readers: Vec<_> = (0..num_readers).map(reader).collect();
+  let writers: Vec<_> = (0..num_writers).map(writer).collect();
 
   for t in readers {
     t.join().expect("Reader panicked");
@@ -290,21 +312,1271 @@ fn rlu_hold_locks() {
     let rglobal = wrapped_int64_obj.rlu_global;
 
     let id = rlu_thread_init(rglobal);
-    rlu_reader_lock(rglobal, id);
-    let val = rlu_dereference(rglobal, id, obj);
-    assert_eq!(*val, 1000 * num_writers);
-    rlu_reader_unlock(rglobal, id);
+    rlu_reader_lock(rglobal, id).unwrap();
+    let val = rlu_dereference(rglobal, id, obj).unwrap();
+    assert_eq!(*val, num_increments * num_writers);
+    rlu_reader_unlock(rglobal, id).unwrap();
 
+    drop(Box::from_raw(obj));
   }
-  
-  
+
+  unsafe { RluGlobal::destroy(rlu_global) };
 }
 
 
 #[test_log::test]
 fn single_read_write_hundred() {
-  for i in 0..100 {
+  for i in 0..rounds(100) {
     println!("Iteration {i}:");
     rlu_single_read_single_writer();
   }
 }
+
+
+fn find_first_above(global: &RluGlobal, thread: RluThreadToken, objs: &[Rlu<u64>], min: u64) -> Option<u64> {
+  let section = global.read(thread).unwrap();
+  for obj in objs {
+    let val = *section.dereference(obj);
+    if val > min {
+      return Some(val); // section is unlocked on the way out
+    }
+  }
+  None
+}
+
+#[test_log::test]
+fn rlu_guard_read_write() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let obj = rlu_global.alloc(2u64);
+
+  let mut thread = rlu_global.register();
+
+  {
+    let section = thread.read();
+    assert_eq!(*section.dereference(&obj), 2);
+
+    // reads so far stay valid, the section now commits when it ends
+    let mut section = section.upgrade();
+    let locked = section.lock(&obj).unwrap();
+    *locked += 1;
+
+    // we see our own copy before committing
+    assert_eq!(*section.dereference(&obj), 3);
+  }
+
+  let section = thread.read();
+  assert_eq!(*section.dereference(&obj), 3);
+}
+
+#[test_log::test]
+fn rlu_guard_abort() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let obj = rlu_global.alloc(7u64);
+
+  let mut thread = rlu_global.register();
+
+  let mut section = thread.read().upgrade();
+  *section.lock(&obj).unwrap() = 100;
+  section.abort();
+
+  let mut section = thread.read().upgrade();
+  assert_eq!(*section.dereference(&obj), 7);
+
+  // the object was unlocked by the abort
+  assert!(section.lock(&obj).is_ok());
+}
+
+#[test_log::test]
+fn rlu_raw_api_errors() {
+  let config = RluConfig { write_log_capacity: 1, free_list_capacity: 1, ..RluConfig::default() };
+  let rlu_global = RluGlobal::init_with_config(tracked(config));
+  let (mut a, mut b) = unsafe { ((*rlu_global).alloc(1u64), (*rlu_global).alloc(2u64)) };
+
+  assert_eq!(rlu_reader_lock(ptr::null_mut::<RluGlobal>(), 0), Err(RluError::BadThread));
+  assert_eq!(rlu_reader_lock(rlu_global, 0), Err(RluError::BadThread));
+  assert_eq!(rlu_dereference(ptr::null_mut::<RluGlobal>(), 0, &mut a), Err(RluError::BadThread));
+
+  let id = rlu_thread_init(rlu_global);
+  assert_eq!(rlu_reader_unlock(rlu_global, id), Err(RluError::NotInSection));
+  assert_eq!(rlu_free(rlu_global, id, &mut a), Err(RluError::NotInSection));
+  assert_eq!(rlu_dereference(rlu_global, id, &mut a), Err(RluError::NotInSection));
+
+  rlu_reader_lock(rlu_global, id).unwrap();
+  assert_eq!(rlu_thread_exit(rlu_global, id), Err(RluError::NestedSection));
+
+  // locking needs a write section
+  assert_eq!(rlu_try_lock(rlu_global, id, &mut a), Err(RluError::NotInSection));
+  rlu_upgrade(rlu_global, id).unwrap();
+  assert_eq!(rlu_try_lock(rlu_global, id, ptr::null_mut::<Rlu<u64>>()), Err(RluError::NullObject));
+  assert!(rlu_try_lock(rlu_global, id, &mut a).is_ok());
+  assert_eq!(rlu_try_lock(rlu_global, id, &mut b), Err(RluError::LogFull));
+
+  assert_eq!(rlu_free(rlu_global, id, ptr::null_mut::<Rlu<u64>>()), Err(RluError::NullObject));
+  rlu_free(rlu_global, id, &mut b).unwrap();
+  assert_eq!(rlu_free(rlu_global, id, &mut a), Err(RluError::FreeListFull));
+
+  // the section is still usable after all of that
+  rlu_reader_unlock(rlu_global, id).unwrap();
+  rlu_reader_lock(rlu_global, id).unwrap();
+  assert_eq!(unsafe { *rlu_dereference(rlu_global, id, &mut a).unwrap() }, 1);
+  rlu_reader_unlock(rlu_global, id).unwrap();
+
+  rlu_thread_exit(rlu_global, id).unwrap();
+  assert_eq!(rlu_reader_lock(rlu_global, id), Err(RluError::BadThread));
+  unsafe { RluGlobal::destroy(rlu_global) };
+}
+
+#[test_log::test]
+#[should_panic(expected = "free_list_capacity must be at least 1")]
+fn rlu_free_list_capacity_zero() {
+  RluGlobal::new(RluConfig { free_list_capacity: 0, ..RluConfig::default() });
+}
+
+#[test_log::test]
+fn rlu_guard_early_return() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let objs: Vec<_> = (0..10).map(|i| rlu_global.alloc(i)).collect();
+
+  let thread = rlu_global.register();
+
+  // every call leaves through a different path, a leaked section would make the
+  // next reader lock panic
+  for min in 0..12 {
+    let expected = if min < 9 { Some(min + 1) } else { None };
+    assert_eq!(find_first_above(rlu_global, thread.token(), &objs, min), expected);
+  }
+}
+
+#[test_log::test]
+fn rlu_guard_concurrent_writers() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let obj = rlu_global.alloc(0u64);
+
+  let num_writers = 4;
+  let num_increments = rounds(500) as u64;
+
+  thread::scope(|s| {
+    for _ in 0..num_writers {
+      s.spawn(|| {
+        let mut thread = rlu_global.register();
+        for _ in 0..num_increments {
+          loop {
+            let mut section = thread.read().upgrade();
+            match section.lock(&obj) {
+              Ok(val) => {
+                *val += 1;
+                break;
+              }
+              Err(_) => section.abort(),
+            }
+          }
+        }
+      });
+    }
+
+    s.spawn(|| {
+      let mut thread = rlu_global.register();
+      for _ in 0..rounds(100) {
+        let section = thread.read();
+        let before = *section.dereference(&obj);
+        thread::sleep(time::Duration::from_millis(1));
+        assert_eq!(before, *section.dereference(&obj));
+      }
+    });
+  });
+
+  let mut thread = rlu_global.register();
+  assert_eq!(*thread.read().dereference(&obj), num_writers * num_increments);
+}
+
+#[test_log::test]
+fn rlu_thread_slot_reuse() {
+  let rlu_global: *mut RluGlobal = RluGlobal::init();
+
+  let id = rlu_thread_init(rlu_global);
+  for _ in 0..100 {
+    let tmp = rlu_thread_init(rlu_global);
+    assert_eq!(tmp, id + 1);
+    rlu_thread_exit(rlu_global, tmp).unwrap();
+  }
+
+  rlu_thread_exit(rlu_global, id).unwrap();
+  assert_eq!(rlu_thread_init(rlu_global), id);
+
+  unsafe { RluGlobal::destroy(rlu_global) };
+}
+
+#[test_log::test]
+fn rlu_current_thread_registers_once() {
+  let rlu_global = Arc::new(RluGlobal::new(tracked(RluConfig::default())));
+
+  let id = RluGlobal::current_thread(&rlu_global);
+  assert_eq!(RluGlobal::current_thread(&rlu_global), id);
+
+  let on_new_thread = || {
+    let rlu_global = rlu_global.clone();
+    thread::spawn(move || RluGlobal::current_thread(&rlu_global).thread_id()).join().unwrap()
+  };
+  let other = on_new_thread();
+  assert_ne!(other, id.thread_id());
+  // the thread gave its slot back when it exited
+  assert_eq!(on_new_thread(), other);
+
+  // a thread that outlives the domain leaves it alone
+  let (dropped, drop_rx) = mpsc::channel::<()>();
+  let late = {
+    let rlu_global = rlu_global.clone();
+    thread::spawn(move || {
+      RluGlobal::current_thread(&rlu_global);
+      drop(rlu_global);
+      drop_rx.recv().unwrap();
+    })
+  };
+  drop(rlu_global);
+  dropped.send(()).unwrap();
+  late.join().unwrap();
+}
+
+#[test_log::test]
+fn rlu_write_across_domains() {
+  let policy = BoundedRetries { max_retries: 2, policy: YieldRetry };
+  let config = RluConfig { contention: Arc::new(policy), ..RluConfig::default() };
+  let accounts = &RluGlobal::new(tracked(config));
+  let ledger = &RluGlobal::new(tracked(RluConfig::default()));
+  assert_ne!(accounts.id(), ledger.id());
+  let balance = accounts.alloc(10u64);
+  let entries = ledger.alloc(Vec::<u64>::new());
+
+  let thread = accounts.register();
+  let ledger_thread = ledger.register();
+  let (token, ledger_token) = (thread.token(), ledger_thread.token());
+  let mut reader = accounts.register();
+  let mut owner = ledger.register();
+  let owner_id = owner.thread_id();
+
+  let transfer = |amount: u64| {
+    accounts.write_across(token, ledger, ledger_token, |tx, ledger_tx| {
+      *tx.lock(&balance)? -= amount;
+      ledger_tx.lock(&entries)?.push(amount);
+      Ok(())
+    })
+  };
+
+  // a conflict in one domain rolls back the other as well
+  let mut section = owner.read().upgrade();
+  section.lock(&entries).unwrap();
+  assert_eq!(transfer(3), Err(RluError::Conflict { owner: owner_id }));
+  section.abort();
+  assert_eq!(read_value(&mut reader, &balance), 10);
+  assert_eq!(accounts.stats(), RluStats { retries: 2, failed_writes: 1 });
+
+  transfer(3).unwrap();
+  assert_eq!(read_value(&mut reader, &balance), 7);
+  assert_eq!(*owner.read().dereference(&entries), [3]);
+}
+
+#[test_log::test]
+fn rlu_write_across_nested_in_one_domain() {
+  let a = &RluGlobal::new(tracked(RluConfig::default()));
+  let b = &RluGlobal::new(tracked(RluConfig::default()));
+  let obj = b.alloc(0u64);
+
+  let thread = a.register();
+  let b_thread = b.register();
+  let (token, b_token) = (thread.token(), b_thread.token());
+  let mut reader = b.register();
+
+  {
+    let _outer = a.read(token).unwrap();
+    let res = a.write_across(token, b, b_token, |_, b_tx| {
+      *b_tx.lock(&obj)? = 42;
+      Err::<(), _>(RluError::Conflict { owner: 0 })
+    });
+    assert_eq!(res, Err(RluError::NestedSection));
+  }
+  // nothing ran in b either
+  assert_eq!(read_value(&mut reader, &obj), 0);
+}
+
+#[test_log::test]
+#[should_panic(expected = "was used with domain")]
+fn rlu_token_of_another_domain() {
+  let first = &RluGlobal::new(RluConfig::default());
+  let second = &RluGlobal::new(RluConfig::default());
+  let thread = first.register();
+  let _ = second.read(thread.token());
+}
+
+#[test_log::test]
+#[should_panic(expected = "needs two different domains")]
+fn rlu_write_across_one_domain() {
+  let rlu_global = &RluGlobal::new(RluConfig::default());
+  let first = rlu_global.register();
+  let second = rlu_global.register();
+  let _ = rlu_global.write_across(first.token(), rlu_global, second.token(), |_, _| Ok(()));
+}
+
+#[cfg(debug_assertions)]
+#[test_log::test]
+#[should_panic(expected = "an object of domain")]
+fn rlu_object_of_another_domain() {
+  let first = &RluGlobal::new(tracked(RluConfig::default()));
+  let second = &RluGlobal::new(RluConfig::default());
+  let obj = first.alloc(0u64);
+  let mut thread = second.register();
+  let section = thread.read();
+  section.dereference(&obj);
+}
+
+#[test_log::test]
+fn rlu_objects_of_many_types() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let count = rlu_global.alloc(0u64);
+  let delta = rlu_global.alloc(0i64); // the same layout as count, its copies share slots
+  let name = rlu_global.alloc(String::from("a"));
+  let drops = Arc::new(AtomicUsize::new(0));
+  let counter = rlu_global.alloc(DropCounter(drops.clone()));
+
+  let mut thread = rlu_global.register();
+  let mut reader = rlu_global.register();
+
+  // an abort drops the copies, whatever their types
+  {
+    let mut section = thread.read().upgrade();
+    *section.lock(&count).unwrap() += 1;
+    section.lock(&name).unwrap().push('b');
+    section.lock(&counter).unwrap();
+    *section.lock(&delta).unwrap() -= 1;
+    section.abort();
+  }
+  assert_eq!(drops.load(Ordering::SeqCst), 1);
+
+  thread.write(|tx| {
+    *tx.lock(&count)? += 1;
+    tx.lock(&name)?.push('b');
+    *tx.lock(&delta)? -= 1;
+    unsafe { tx.free(counter)? };
+    Ok(())
+  }).unwrap();
+  assert_eq!(drops.load(Ordering::SeqCst), 2);
+
+  let section = reader.read();
+  assert_eq!(*section.dereference(&count), 1);
+  assert_eq!(*section.dereference(&delta), -1);
+  assert_eq!(section.dereference(&name), "ab");
+}
+
+#[test_log::test]
+fn rlu_objects_of_many_types_commit_together() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let total = rlu_global.alloc(0u64);
+  let history = rlu_global.alloc(Vec::<u64>::new());
+
+  thread::scope(|s| {
+    for _ in 0..2 {
+      s.spawn(|| {
+        let mut thread = rlu_global.register();
+        for i in 0..rounds(200) as u64 {
+          thread.write(|tx| {
+            *tx.lock(&total)? += i;
+            tx.lock(&history)?.push(i);
+            Ok(())
+          }).unwrap();
+        }
+      });
+    }
+    s.spawn(|| {
+      let mut thread = rlu_global.register();
+      for _ in 0..rounds(500) {
+        let section = thread.read();
+        let sum: u64 = section.dereference(&history).iter().sum();
+        assert_eq!(*section.dereference(&total), sum);
+      }
+    });
+  });
+}
+
+#[test_log::test]
+fn rlu_thread_handles_recycled() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let obj = rlu_global.alloc(0u64);
+
+  // far more registrations than there are slots, with a writer synchronizing against
+  // threads that come and go
+  thread::scope(|s| {
+    s.spawn(|| {
+      let mut thread = rlu_global.register();
+      for _ in 0..200 {
+        loop {
+          let mut section = thread.read().upgrade();
+          match section.lock(&obj) {
+            Ok(val) => {
+              *val += 1;
+              break;
+            }
+            Err(_) => section.abort(),
+          }
+        }
+      }
+    });
+
+    for _ in 0..4 {
+      s.spawn(|| {
+        for _ in 0..50 {
+          let mut thread = rlu_global.register();
+          let section = thread.read();
+          let val = *section.dereference(&obj);
+          assert!(val <= 200);
+        }
+      });
+    }
+  });
+
+  let mut thread = rlu_global.register();
+  assert_eq!(*thread.read().dereference(&obj), 200);
+}
+
+#[test_log::test]
+fn rlu_many_registered_threads() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let obj = rlu_global.alloc(0u64);
+
+  let num_readers = 300;
+  let barrier = std::sync::Barrier::new(num_readers + 1);
+
+  let ids: Vec<usize> = thread::scope(|s| {
+    let readers: Vec<_> = (0..num_readers).map(|_| s.spawn(|| {
+      let mut thread = rlu_global.register();
+      // keep every registration alive at the same time
+      barrier.wait();
+      for _ in 0..10 {
+        let section = thread.read();
+        let before = *section.dereference(&obj);
+        thread::yield_now();
+        assert_eq!(before, *section.dereference(&obj));
+      }
+      thread.thread_id()
+    })).collect();
+
+    s.spawn(|| {
+      let mut thread = rlu_global.register();
+      barrier.wait();
+      for _ in 0..50 {
+        let mut section = thread.read().upgrade();
+        *section.lock(&obj).unwrap() += 1;
+      }
+    });
+
+    readers.into_iter().map(|t| t.join().unwrap()).collect()
+  });
+
+  let mut sorted = ids.clone();
+  sorted.sort();
+  sorted.dedup();
+  assert_eq!(sorted.len(), num_readers);
+  assert!(sorted.iter().any(|&id| id >= 256));
+
+  let mut thread = rlu_global.register();
+  assert_eq!(*thread.read().dereference(&obj), 50);
+}
+
+#[test_log::test]
+fn rlu_lock_thousands_in_one_section() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  // enough to need several chunks of the write log
+  let objs: Vec<_> = (0..rounds(5000)).map(|_| rlu_global.alloc(0u64)).collect();
+
+  thread::scope(|s| {
+    s.spawn(|| {
+      let mut thread = rlu_global.register();
+      for round in 1..=5 {
+        let mut section = thread.read().upgrade();
+        for obj in &objs {
+          let val = section.lock(obj).unwrap();
+          assert_eq!(*val, round - 1);
+          *val = round;
+        }
+        // locking again hands back the copy we already have
+        assert_eq!(*section.lock(&objs[0]).unwrap(), round);
+      }
+    });
+
+    // every section sees all of a commit or none of it
+    s.spawn(|| {
+      let mut thread = rlu_global.register();
+      for _ in 0..20 {
+        let section = thread.read();
+        let first = *section.dereference(&objs[0]);
+        assert!(objs.iter().all(|obj| *section.dereference(obj) == first));
+      }
+    });
+  });
+
+  let mut thread = rlu_global.register();
+  let section = thread.read();
+  assert!(objs.iter().all(|obj| *section.dereference(obj) == 5));
+}
+
+#[derive(Clone, Debug)]
+struct DropCounter(Arc<AtomicUsize>);
+
+impl Drop for DropCounter {
+  fn drop(&mut self) {
+    self.0.fetch_add(1, Ordering::SeqCst);
+  }
+}
+
+#[test_log::test]
+fn rlu_free_many_in_one_section() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let objs: Vec<_> = (0..rounds(5000)).map(|i| rlu_global.alloc(i as u64)).collect();
+
+  let mut thread = rlu_global.register();
+  {
+    let mut section = thread.read().upgrade();
+    for obj in objs {
+      unsafe { section.free(obj) }.unwrap();
+    }
+  }
+}
+
+#[test_log::test]
+fn rlu_free_high_water_flushes() {
+  let drops = Arc::new(AtomicUsize::new(0));
+  let config = RluConfig { free_high_water: 10, ..RluConfig::default() };
+  let rlu_global = &RluGlobal::new(tracked(config));
+
+  let mut thread = rlu_global.register();
+
+  // below the mark the frees wait for the next commit
+  {
+    let mut section = thread.read().upgrade();
+    for _ in 0..9 {
+      unsafe { section.free(rlu_global.alloc(DropCounter(drops.clone()))) }.unwrap();
+    }
+  }
+  assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+  // reaching it flushes everything pending without any writes
+  {
+    let mut section = thread.read().upgrade();
+    unsafe { section.free(rlu_global.alloc(DropCounter(drops.clone()))) }.unwrap();
+  }
+  assert_eq!(drops.load(Ordering::SeqCst), 10);
+
+  // frees of an aborted section are forgotten, the object is still alive
+  let kept = rlu_global.alloc(DropCounter(drops.clone()));
+  {
+    let mut section = thread.read().upgrade();
+    unsafe { section.free(kept) }.unwrap();
+    section.abort();
+  }
+  assert_eq!(drops.load(Ordering::SeqCst), 10);
+
+  // exiting flushes the rest
+  {
+    let mut section = thread.read().upgrade();
+    unsafe { section.free(rlu_global.alloc(DropCounter(drops.clone()))) }.unwrap();
+  }
+  drop(thread);
+  assert_eq!(drops.load(Ordering::SeqCst), 11);
+}
+
+fn deferred_config() -> RluConfig {
+  RluConfig { mode: RluMode::Deferred, ..RluConfig::default() }
+}
+
+fn increment(thread: &mut RluThread<'_>, obj: &Rlu<u64>) {
+  thread.write(|tx| {
+    *tx.lock(obj)? += 1;
+    Ok(())
+  }).unwrap();
+}
+
+fn read_value(thread: &mut RluThread<'_>, obj: &Rlu<u64>) -> u64 {
+  *thread.read().dereference(obj)
+}
+
+#[test_log::test]
+fn rlu_deferred_writes_stay_private() {
+  let rlu_global = &RluGlobal::new(tracked(deferred_config()));
+  let obj = rlu_global.alloc(0u64);
+
+  let mut writer = rlu_global.register();
+  let mut reader = rlu_global.register();
+
+  increment(&mut writer, &obj);
+
+  // the writer sees its own copy, everyone else the original
+  assert_eq!(read_value(&mut writer, &obj), 1);
+  assert_eq!(read_value(&mut reader, &obj), 0);
+
+  writer.sync_and_writeback();
+  assert_eq!(read_value(&mut reader, &obj), 1);
+}
+
+#[test_log::test]
+fn rlu_deferred_relock_own_copy() {
+  let rlu_global = &RluGlobal::new(tracked(deferred_config()));
+  let obj = rlu_global.alloc(0u64);
+
+  let mut writer = rlu_global.register();
+  let mut reader = rlu_global.register();
+  let writer_id = writer.thread_id();
+
+  increment(&mut writer, &obj);
+
+  // the copy belongs to an earlier section: locking it again fails once and the
+  // abort writes it back
+  let mut section = writer.read().upgrade();
+  assert_eq!(section.lock(&obj).err(), Some(RluError::Conflict { owner: writer_id }));
+  section.abort();
+  assert_eq!(read_value(&mut reader, &obj), 1);
+
+  increment(&mut writer, &obj);
+  assert_eq!(read_value(&mut writer, &obj), 2);
+  assert_eq!(read_value(&mut reader, &obj), 1);
+
+  // exiting writes back whatever is left
+  drop(writer);
+  assert_eq!(read_value(&mut reader, &obj), 2);
+}
+
+#[test_log::test]
+fn rlu_deferred_log_limit() {
+  let config = RluConfig { deferred_log_limit: 10, ..deferred_config() };
+  let rlu_global = &RluGlobal::new(tracked(config));
+  let objs: Vec<_> = (0..10).map(|_| rlu_global.alloc(0u64)).collect();
+
+  let mut writer = rlu_global.register();
+  let mut reader = rlu_global.register();
+
+  for obj in &objs[..9] {
+    increment(&mut writer, obj);
+  }
+  assert!(objs.iter().all(|obj| read_value(&mut reader, obj) == 0));
+
+  increment(&mut writer, &objs[9]);
+  assert!(objs.iter().all(|obj| read_value(&mut reader, obj) == 1));
+}
+
+#[test_log::test]
+fn rlu_deferred_conflict_requests_sync() {
+  let rlu_global = &RluGlobal::new(tracked(deferred_config()));
+  let obj = rlu_global.alloc(0u64);
+  let done = std::sync::atomic::AtomicBool::new(false);
+
+  let mut owner = rlu_global.register();
+  increment(&mut owner, &obj);
+
+  thread::scope(|s| {
+    s.spawn(|| {
+      let mut other = rlu_global.register();
+      increment(&mut other, &obj);
+      done.store(true, Ordering::SeqCst);
+    });
+
+    // the owner only has to keep running sections to honour the request
+    while !done.load(Ordering::SeqCst) {
+      drop(owner.read());
+      thread::yield_now();
+    }
+  });
+
+  assert_eq!(read_value(&mut owner, &obj), 2);
+}
+
+#[test_log::test]
+fn rlu_deferred_conflict_with_idle_owner() {
+  let rlu_global = &RluGlobal::new(tracked(deferred_config()));
+  let obj = rlu_global.alloc(0u64);
+
+  let mut owner = rlu_global.register();
+  let mut other = rlu_global.register();
+  let mut reader = rlu_global.register();
+  increment(&mut owner, &obj);
+
+  // the owner runs no more sections, the other writer writes its copy back for it
+  increment(&mut other, &obj);
+  assert_eq!(read_value(&mut reader, &obj), 1);
+  other.sync_and_writeback();
+  assert_eq!(read_value(&mut reader, &obj), 2);
+  assert_eq!(read_value(&mut owner, &obj), 2);
+}
+
+#[test_log::test]
+fn rlu_deferred_concurrent_writers() {
+  let rlu_global = &RluGlobal::new(tracked(deferred_config()));
+  let objs: Vec<_> = (0..4).map(|_| rlu_global.alloc(0u64)).collect();
+  let num_increments = rounds(500);
+
+  thread::scope(|s| {
+    for t in 0..4 {
+      let objs = &objs;
+      s.spawn(move || {
+        let mut thread = rlu_global.register();
+        for i in 0..num_increments {
+          increment(&mut thread, &objs[(t + i) % objs.len()]);
+        }
+      });
+    }
+  });
+
+  let mut thread = rlu_global.register();
+  let total: u64 = objs.iter().map(|obj| read_value(&mut thread, obj)).sum();
+  assert_eq!(total, 4 * num_increments as u64);
+}
+
+#[test_log::test]
+fn rlu_write_returns_value() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let objs: Vec<_> = (0..4).map(|i| rlu_global.alloc(i as u64)).collect();
+  let mut thread = rlu_global.register();
+
+  let sum = thread.write(|tx| {
+    let mut sum = 0;
+    for obj in &objs {
+      let val = tx.lock(obj)?;
+      *val *= 10;
+      sum += *val;
+    }
+    Ok(sum)
+  });
+
+  assert_eq!(sum, Ok(60));
+  assert_eq!(*thread.read().dereference(&objs[3]), 30);
+}
+
+fn move_units(policy: Arc<dyn ContentionPolicy>) {
+  let config = RluConfig { contention: policy, ..RluConfig::default() };
+  let rlu_global = &RluGlobal::new(tracked(config));
+  let a = rlu_global.alloc(0i64);
+  let b = rlu_global.alloc(0i64);
+
+  let num_writers = 4;
+  let num_increments = 50;
+  let attempts = AtomicUsize::new(0);
+
+  // each write moves one unit from a to b through both locks, so a + b stays at 0
+  thread::scope(|s| {
+    for _ in 0..num_writers {
+      s.spawn(|| {
+        let mut thread = rlu_global.register();
+        for _ in 0..num_increments {
+          thread.write(|tx| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            *tx.lock(&a)? -= 1;
+            thread::yield_now();
+            *tx.lock(&b)? += 1;
+            Ok(())
+          }).unwrap();
+        }
+      });
+    }
+
+    s.spawn(|| {
+      let mut thread = rlu_global.register();
+      for _ in 0..100 {
+        let section = thread.read();
+        assert_eq!(section.dereference(&a) + section.dereference(&b), 0);
+      }
+    });
+  });
+
+  let mut thread = rlu_global.register();
+  assert_eq!(*thread.read().dereference(&b), (num_writers * num_increments) as i64);
+
+  let stats = rlu_global.stats();
+  assert_eq!(attempts.load(Ordering::SeqCst), num_writers * num_increments + stats.retries);
+  assert_eq!(stats.failed_writes, 0);
+}
+
+#[test_log::test]
+fn rlu_write_retries_on_conflict() {
+  move_units(Arc::new(ImmediateRetry));
+  move_units(Arc::new(ExponentialBackoff::default()));
+  move_units(Arc::new(YieldRetry));
+}
+
+#[test_log::test]
+fn rlu_write_bounded_retries_give_up() {
+  let policy = BoundedRetries { max_retries: 3, policy: YieldRetry };
+  let config = RluConfig { contention: Arc::new(policy), ..RluConfig::default() };
+  let rlu_global = &RluGlobal::new(tracked(config));
+  let obj = rlu_global.alloc(0u64);
+
+  let mut owner = rlu_global.register();
+  let mut other = rlu_global.register();
+  let owner_id = owner.thread_id();
+
+  // the owner keeps obj locked while the other thread tries to write it
+  let mut section = owner.read().upgrade();
+  *section.lock(&obj).unwrap() = 1;
+
+  let res = other.write(|tx| {
+    *tx.lock(&obj)? = 2;
+    Ok(())
+  });
+  assert_eq!(res, Err(RluError::Conflict { owner: owner_id }));
+  assert_eq!(rlu_global.stats(), RluStats { retries: 3, failed_writes: 1 });
+
+  drop(section);
+  increment(&mut other, &obj);
+  assert_eq!(read_value(&mut other, &obj), 2);
+}
+
+#[test_log::test]
+fn rlu_nested_sections() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let g = rlu_global as *const RluGlobal as *mut RluGlobal;
+  let obj = rlu_global.alloc(1u64);
+
+  let thread = rlu_global.register();
+  let id = thread.thread_id();
+  let token = thread.token();
+  let mut other = rlu_global.register();
+
+  {
+    let mut outer = rlu_global.read(token).unwrap();
+    // like a library call that writes on the same thread
+    {
+      let mut tx = outer.nest().upgrade();
+      *tx.lock(&obj).unwrap() += 1;
+      assert_eq!(rlu_section_depth(g, id), Ok(2));
+    }
+    assert_eq!(rlu_section_depth(g, id), Ok(1));
+
+    // the domain only opens outermost sections
+    assert_eq!(rlu_global.read(token).err(), Some(RluError::NestedSection));
+    assert_eq!(rlu_global.write(token, |_| Ok(())), Err(RluError::NestedSection));
+
+    // it joined the outer section, which has not committed yet
+    assert_eq!(*outer.dereference(&obj), 2);
+    assert_eq!(read_value(&mut other, &obj), 1);
+
+    // only the outermost section can abort
+    rlu_reader_lock(g, id).unwrap();
+    assert_eq!(rlu_abort(g, id), Err(RluError::NestedSection));
+    rlu_reader_unlock(g, id).unwrap();
+  }
+  assert_eq!(rlu_section_depth(g, id), Ok(0));
+  assert_eq!(read_value(&mut other, &obj), 2);
+}
+
+#[test_log::test]
+fn rlu_nested_write_leaves_retries_to_outer() {
+  let policy = BoundedRetries { max_retries: 2, policy: YieldRetry };
+  let config = RluConfig { contention: Arc::new(policy), ..RluConfig::default() };
+  let rlu_global = &RluGlobal::new(tracked(config));
+  let objs = [rlu_global.alloc(0u64), rlu_global.alloc(0u64)];
+
+  let thread = rlu_global.register();
+  let token = thread.token();
+  let mut owner = rlu_global.register();
+  let owner_id = owner.thread_id();
+
+  let mut section = owner.read().upgrade();
+  *section.lock(&objs[1]).unwrap() = 1;
+
+  let res = rlu_global.write(token, |tx| {
+    *tx.lock(&objs[0])? += 1;
+    let mut inner = tx.nest();
+    *inner.lock(&objs[1])? += 1;
+    Ok(())
+  });
+  assert_eq!(res, Err(RluError::Conflict { owner: owner_id }));
+  // the nested section did not retry on its own
+  assert_eq!(rlu_global.stats(), RluStats { retries: 2, failed_writes: 1 });
+
+  section.abort();
+  assert_eq!(read_value(&mut owner, &objs[0]), 0);
+}
+
+#[test_log::test]
+fn rlu_write_only_retries_conflicts() {
+  let config = RluConfig { write_log_capacity: 1, ..RluConfig::default() };
+  let rlu_global = &RluGlobal::new(tracked(config));
+  let objs = [rlu_global.alloc(0u64), rlu_global.alloc(0u64)];
+
+  let mut thread = rlu_global.register();
+  let res = thread.write(|tx| {
+    for obj in &objs {
+      *tx.lock(obj)? += 1;
+    }
+    Ok(())
+  });
+  assert_eq!(res, Err(RluError::LogFull));
+  assert_eq!(rlu_global.stats(), RluStats::default());
+
+  // the section was aborted
+  assert_eq!(read_value(&mut thread, &objs[0]), 0);
+}
+
+fn wait_for_slow_reader(wait: RluWaitStrategy) {
+  let config = RluConfig { wait, ..RluConfig::default() };
+  let rlu_global = &RluGlobal::new(tracked(config));
+  let obj = rlu_global.alloc(0u64);
+  let reader_done = std::sync::atomic::AtomicBool::new(false);
+  let in_section = std::sync::Barrier::new(2);
+
+  thread::scope(|s| {
+    s.spawn(|| {
+      let mut reader = rlu_global.register();
+      let section = reader.read();
+      in_section.wait();
+      thread::sleep(time::Duration::from_millis(50));
+      assert_eq!(*section.dereference(&obj), 0);
+      reader_done.store(true, Ordering::SeqCst);
+    });
+
+    // the commit has to wait until the reader's section is over
+    let mut writer = rlu_global.register();
+    in_section.wait();
+    increment(&mut writer, &obj);
+    assert!(reader_done.load(Ordering::SeqCst));
+  });
+}
+
+#[test_log::test]
+fn rlu_synchronize_wait_strategies() {
+  wait_for_slow_reader(RluWaitStrategy::spin());
+  wait_for_slow_reader(RluWaitStrategy { spins: 10, yields: 10, park: false });
+  wait_for_slow_reader(RluWaitStrategy { spins: 0, yields: 0, park: true });
+  wait_for_slow_reader(RluWaitStrategy::default());
+}
+
+#[test_log::test]
+fn rlu_synchronize_waits_for_open_sections() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let reader_done = std::sync::atomic::AtomicBool::new(false);
+  let in_section = std::sync::Barrier::new(2);
+
+  thread::scope(|s| {
+    s.spawn(|| {
+      let mut reader = rlu_global.register();
+      let _section = reader.read();
+      in_section.wait();
+      thread::sleep(time::Duration::from_millis(50));
+      reader_done.store(true, Ordering::SeqCst);
+    });
+
+    // not registered, so nothing of ours can be in a section
+    in_section.wait();
+    rlu_global.synchronize();
+    assert!(reader_done.load(Ordering::SeqCst));
+  });
+
+  // nobody in a section: returns right away
+  rlu_global.synchronize();
+}
+
+#[test_log::test]
+fn rlu_call_after_grace_period() {
+  let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+  let drops = Arc::new(AtomicUsize::new(0));
+
+  // data outside of RLU that readers look at inside their sections
+  let shared = std::sync::atomic::AtomicPtr::new(Box::into_raw(Box::new(DropCounter(drops.clone()))));
+  let stop = std::sync::atomic::AtomicBool::new(false);
+
+  thread::scope(|s| {
+    for _ in 0..2 {
+      s.spawn(|| {
+        let mut reader = rlu_global.register();
+        while !stop.load(Ordering::SeqCst) {
+          let _section = reader.read();
+          let counter = unsafe { &*shared.load(Ordering::SeqCst) };
+          assert!(counter.0.load(Ordering::SeqCst) <= 50);
+        }
+      });
+    }
+
+    for round in 0..50 {
+      // readers may still be looking at the old value, so no Box until the callback
+      let old = std::sync::atomic::AtomicPtr::new(shared.swap(Box::into_raw(Box::new(DropCounter(drops.clone()))), Ordering::SeqCst));
+      rlu_global.call_after_grace_period(move || drop(unsafe { Box::from_raw(old.into_inner()) }));
+      assert!(drops.load(Ordering::SeqCst) <= round);
+      rlu_global.synchronize();
+      assert_eq!(drops.load(Ordering::SeqCst), round + 1);
+    }
+    stop.store(true, Ordering::SeqCst);
+  });
+
+  drop(unsafe { Box::from_raw(shared.load(Ordering::SeqCst)) });
+  assert_eq!(drops.load(Ordering::SeqCst), 51);
+}
+
+// Remembers which thread dropped it.
+#[derive(Clone)]
+struct DropWitness(Arc<Mutex<Vec<Option<String>>>>);
+
+impl Drop for DropWitness {
+  fn drop(&mut self) {
+    let name = thread::current().name().map(String::from);
+    self.0.lock().unwrap().push(name);
+  }
+}
+
+#[test_log::test]
+fn rlu_background_reclaimer() {
+  // every section below frees 10 objects and commits
+  let config = RluConfig { free_high_water: 10, ..RluConfig::default() };
+  let rlu_global = &RluGlobal::new(tracked(config));
+  let witness = DropWitness(Arc::new(Mutex::new(Vec::new())));
+  let objs: Vec<_> = (0..200).map(|_| rlu_global.alloc(witness.clone())).collect();
+
+  rlu_global.start_reclaimer();
+  rlu_global.start_reclaimer();
+
+  let mut thread = rlu_global.register();
+  for chunk in objs[..150].chunks(10) {
+    let mut section = thread.read().upgrade();
+    for obj in chunk {
+      unsafe { section.free(*obj) }.unwrap();
+    }
+  }
+
+  // shutting down drains whatever is still queued
+  rlu_global.stop_reclaimer();
+  {
+    let dropped = witness.0.lock().unwrap();
+    assert_eq!(dropped.len(), 150);
+    assert!(dropped.iter().all(|name| name.as_deref() == Some("rlu-reclaimer")));
+  }
+
+  // without it the writer drops them again
+  for chunk in objs[150..].chunks(10) {
+    let mut section = thread.read().upgrade();
+    for obj in chunk {
+      unsafe { section.free(*obj) }.unwrap();
+    }
+  }
+  let dropped = witness.0.lock().unwrap();
+  assert_eq!(dropped.len(), 200);
+  assert!(dropped[150..].iter().all(|name| name.as_deref() != Some("rlu-reclaimer")));
+}
+
+#[test_log::test]
+fn rlu_global_drop_frees_everything() {
+  let drops = Arc::new(AtomicUsize::new(0));
+  let rlu_global: *mut RluGlobal = RluGlobal::init_with_config(tracked(deferred_config()));
+  let rlu_global_obj = unsafe { &*rlu_global };
+  let objs: Vec<_> = (0..10).map(|_| rlu_global_obj.alloc(DropCounter(drops.clone()))).collect();
+
+  // a raw thread that never exits: a deferred copy and two frees stay pending
+  let id = rlu_thread_init(rlu_global);
+  rlu_reader_lock(rlu_global, id).unwrap();
+  rlu_upgrade(rlu_global, id).unwrap();
+  assert!(rlu_try_lock(rlu_global, id, &objs[0] as *const Rlu<DropCounter> as *mut Rlu<DropCounter>).is_ok());
+  rlu_free(rlu_global, id, &objs[1] as *const Rlu<DropCounter> as *mut Rlu<DropCounter>).unwrap();
+  rlu_free(rlu_global, id, &objs[2] as *const Rlu<DropCounter> as *mut Rlu<DropCounter>).unwrap();
+  rlu_reader_unlock(rlu_global, id).unwrap();
+  assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+  // the copy replaces the value it was cloned from, then every object is dropped
+  // once: the pending frees and the ones still alive
+  unsafe { RluGlobal::destroy(rlu_global) };
+  assert_eq!(drops.load(Ordering::SeqCst), 11);
+}
+
+#[test_log::test]
+fn rlu_global_drop_waits_for_callbacks() {
+  let drops = Arc::new(AtomicUsize::new(0));
+  {
+    let rlu_global = RluGlobal::new(RluConfig::default());
+    let counter = DropCounter(drops.clone());
+    rlu_global.call_after_grace_period(move || drop(counter));
+
+    // untracked: objects nobody freed are the caller's business
+    let obj = rlu_global.alloc(1);
+    let mut thread = rlu_global.register();
+    let mut section = thread.read().upgrade();
+    assert_eq!(*section.dereference(&obj), 1);
+    unsafe { section.free(obj) }.unwrap();
+  }
+  assert_eq!(drops.load(Ordering::SeqCst), 1);
+}
+
+// Counts the instances alive, clones included.
+#[derive(Debug)]
+struct Live(Arc<AtomicUsize>);
+
+impl Live {
+  fn new(count: &Arc<AtomicUsize>) -> Live {
+    count.fetch_add(1, Ordering::SeqCst);
+    Live(count.clone())
+  }
+}
+
+impl Clone for Live {
+  fn clone(&self) -> Live {
+    Live::new(&self.0)
+  }
+}
+
+impl Drop for Live {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+#[test_log::test]
+fn rlu_copies_dropped_exactly_once() {
+  for mode in [RluMode::Eager, RluMode::Deferred] {
+    let count = Arc::new(AtomicUsize::new(0));
+    {
+      let rlu_global = &RluGlobal::new(tracked(RluConfig { mode, ..RluConfig::default() }));
+      let objs: Vec<_> = (0..3).map(|_| rlu_global.alloc(Live::new(&count))).collect();
+      let mut thread = rlu_global.register();
+
+      // the copy replaces the original on writeback
+      thread.write(|tx| {
+        tx.lock(&objs[0])?;
+        Ok(())
+      }).unwrap();
+      thread.sync_and_writeback();
+      assert_eq!(count.load(Ordering::SeqCst), 3);
+
+      // the copy of an aborted section is dropped right away
+      let mut section = thread.read().upgrade();
+      assert!(section.lock(&objs[1]).is_ok());
+      assert_eq!(count.load(Ordering::SeqCst), 4);
+      section.abort();
+      assert_eq!(count.load(Ordering::SeqCst), 3);
+
+      // a freed object goes with its pending copy
+      thread.write(|tx| {
+        tx.lock(&objs[2])?;
+        unsafe { tx.free(objs[2]) }.unwrap();
+        Ok(())
+      }).unwrap();
+      thread.sync_and_writeback();
+      assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+    // the domain drops the rest
+    assert_eq!(count.load(Ordering::SeqCst), 0);
+  }
+}
+
+#[test_log::test]
+fn rlu_objects_with_heap_data() {
+  let shared = Arc::new(String::from("shared"));
+  {
+    let rlu_global = &RluGlobal::new(tracked(RluConfig::default()));
+    let obj = rlu_global.alloc((Vec::<String>::new(), shared.clone()));
+
+    let num_writers = 4;
+    let num_pushes = rounds(50);
+
+    thread::scope(|s| {
+      for t in 0..num_writers {
+        s.spawn(move || {
+          let mut thread = rlu_global.register();
+          for i in 0..num_pushes {
+            thread.write(|tx| {
+              tx.lock(&obj)?.0.push(format!("writer {} item {}", t, i));
+              Ok(())
+            }).unwrap();
+          }
+        });
+      }
+
+      s.spawn(|| {
+        let mut thread = rlu_global.register();
+        for _ in 0..rounds(100) {
+          let section = thread.read();
+          let (items, name) = section.dereference(&obj);
+          assert!(items.iter().all(|item| item.starts_with("writer ")));
+          assert_eq!(name.as_str(), "shared");
+        }
+      });
+    });
+
+    let mut thread = rlu_global.register();
+    assert_eq!(thread.read().dereference(&obj).0.len(), num_writers * num_pushes);
+  }
+  assert_eq!(Arc::strong_count(&shared), 1);
+}
+
+#[derive(Clone, Debug)]
+struct Link {
+  val: u64,
+  next: RluPtr<Link>,
+}
+
+#[test_log::test]
+fn rlu_ptr_assign_and_load() {
+  let rlu_global = RluGlobal::new(RluConfig::default());
+  let tail = rlu_global.alloc(Link { val: 2, next: RluPtr::null() });
+  let head = rlu_global.alloc(Link { val: 1, next: tail.into() });
+  let mut thread = rlu_global.register();
+
+  {
+    let section = thread.read();
+    let next = section.dereference(&head).next.load(&section).unwrap();
+    assert_eq!(section.dereference(&next).val, 2);
+    assert!(section.dereference(&tail).next.load(&section).is_none());
+  }
+
+  thread.write(|tx| {
+    let seen = tx.dereference(&head).next;
+    let copy = tx.lock(&head)?;
+    // the copy points at the same original
+    assert_eq!(copy.next, seen);
+    copy.next.assign(None);
+    unsafe { tx.free(tail) }.unwrap();
+    Ok(())
+  }).unwrap();
+
+  let mut section = thread.read().upgrade();
+  assert!(section.dereference(&head).next.is_null());
+  unsafe { section.free(head) }.unwrap();
+}
+
+struct DataPtr(*const u64);
+unsafe impl Send for DataPtr {}
+
+#[test_log::test]
+fn rlu_same_object_sees_through_copies() {
+  let rlu_global = RluGlobal::new(RluConfig::default());
+  let a = rlu_global.alloc(1u64);
+  let b = rlu_global.alloc(1u64);
+  assert_eq!(a, a);
+  assert_ne!(a, b);
+
+  let (locked_tx, locked_rx) = mpsc::channel();
+  let (done_tx, done_rx) = mpsc::channel();
+  thread::scope(|s| {
+    let rlu_global = &rlu_global;
+    s.spawn(move || {
+      let mut thread = rlu_global.register();
+      let mut section = thread.read().upgrade();
+      let copy: *const u64 = section.lock(&a).unwrap();
+      locked_tx.send(DataPtr(copy)).unwrap();
+      // hold the copy until the reader is done comparing
+      done_rx.recv().unwrap();
+      section.abort();
+    });
+
+    let copy = locked_rx.recv().unwrap().0;
+    let mut thread = rlu_global.register();
+    {
+      let section = thread.read();
+      // not committed, so this is the original
+      let original = section.dereference(&a);
+      assert!(!ptr::eq(original, copy));
+      unsafe {
+        assert!(rlu_same_object(copy, original));
+        assert!(!rlu_same_object(copy, section.dereference(&b)));
+      }
+      assert!(section.same_object(section.version(&a), section.version(&a)));
+      assert!(!section.same_object(section.version(&a), section.version(&b)));
+    }
+    done_tx.send(()).unwrap();
+  });
+
+  let mut thread = rlu_global.register();
+  let mut section = thread.read().upgrade();
+  unsafe { section.free(a) }.unwrap();
+  unsafe { section.free(b) }.unwrap();
+}
@@ -1,13 +1,19 @@
 extern crate rand;
 
-use rlu::{RluSet, ConcurrentSet};
+use rlu::{RluSet, ConcurrentSet, RluConfig, RluMode};
+use std::sync::Arc;
 use std::thread;
 
 use rand::{random, thread_rng, Rng};
 
+// Miri is orders of magnitude slower, so the larger tests shrink under it.
+fn rounds(n: i32) -> i32 {
+  if cfg!(miri) { (n + 9) / 10 } else { n }
+}
+
 #[test]
 fn set_simple() {
-  let mut set : RluSet<i32> = RluSet::new();
+  let set : RluSet<i32> = RluSet::new();
 
   assert_eq!(set.len(), 0);
 
@@ -21,7 +27,7 @@ fn set_simple() {
   assert!(set.insert(0));
   
   assert!(set.insert(1));
-  println!("Ins 1: {}", set.to_string());
+  println!("Ins 1: {}", set);
 
   for i in 0..=2 {
     assert!(set.contains(i));
@@ -48,9 +54,10 @@ fn set_simple() {
 
 #[test]
 fn set_thread() {
-  let mut set = RluSet::new();
+  let set = RluSet::new();
+  let n = rounds(1000);
 
-  for i in 0..1000 {
+  for i in 0..n {
     assert!(set.insert(i));
   }
 
@@ -59,20 +66,20 @@ fn set_thread() {
     thread::spawn(move || {
       let mut rng = thread_rng();
 
-      for _ in 0..10000 {
-        let i = rng.gen_range(0, 500) * 2;
+      for _ in 0..rounds(10000) {
+        let i = rng.gen_range(0, n / 2) * 2;
         assert!(set.contains(i));
       }
     })
   };
 
   let writer = || {
-    let mut set = set.clone_ref();
+    let set = set.clone_ref();
     thread::spawn(move || {
       let mut rng = thread_rng();
 
-      for _ in 0..1000 {
-        let i = rng.gen_range(0, 499) * 2 + 1;
+      for _ in 0..n {
+        let i = rng.gen_range(0, n / 2 - 1) * 2 + 1;
         if random() {
           set.insert(i);
         } else {
@@ -82,7 +89,7 @@ fn set_thread() {
     })
   };
 
-  let readers: Vec<_> = (0..16).map(|_| reader()).collect();
+  let readers: Vec<_> = (0..rounds(16)).map(|_| reader()).collect();
   let writers: Vec<_> = (0..4).map(|_| writer()).collect();
 
   for t in readers {
@@ -93,3 +100,206 @@ fn set_thread() {
     t.join().unwrap();
   }
 }
+
+#[test]
+fn set_clone_ref_many() {
+  let set = RluSet::new();
+  assert!(set.insert(1));
+
+  // each clone registers a thread and gives it back when dropped
+  let n = rounds(100);
+  for i in 0..n {
+    let set = set.clone_ref();
+    assert!(set.contains(1));
+    assert!(set.insert(i + 2));
+  }
+
+  let handles: Vec<_> = (0..8).map(|_| {
+    let set = set.clone_ref();
+    thread::spawn(move || {
+      for _ in 0..20 {
+        let set = set.clone_ref();
+        assert!(set.contains(1));
+      }
+    })
+  }).collect();
+
+  for t in handles {
+    t.join().unwrap();
+  }
+
+  assert_eq!(set.len() as i32, n + 1);
+}
+
+#[test]
+fn set_shared_through_arc() {
+  let config = RluConfig { auto_register: true, ..RluConfig::default() };
+  let set = Arc::new(RluSet::with_config(config));
+
+  // one set for all threads, each registers on its first call
+  let n = rounds(400);
+  let workers: Vec<_> = (0..4).map(|t| {
+    let set = set.clone();
+    thread::spawn(move || {
+      for i in (t..n).step_by(4) {
+        assert!(set.insert(i));
+        assert!(set.contains(i));
+      }
+    })
+  }).collect();
+
+  for t in workers {
+    t.join().unwrap();
+  }
+
+  assert_eq!(set.len() as i32, n);
+}
+
+#[test]
+fn set_handle_shared_without_auto_register() {
+  let set = Arc::new(RluSet::new());
+
+  // the handle's thread serves one call at a time, overlapping calls register
+  let n = rounds(400);
+  let workers: Vec<_> = (0..4).map(|t| {
+    let set = set.clone();
+    thread::spawn(move || {
+      for i in (t..n).step_by(4) {
+        assert!(set.insert(i));
+        assert!(set.contains(i));
+      }
+    })
+  }).collect();
+
+  for t in workers {
+    t.join().unwrap();
+  }
+
+  assert_eq!(set.len() as i32, n);
+}
+
+#[test]
+fn set_bulk_delete() {
+  let set = RluSet::new();
+
+  let n = rounds(2000);
+  for i in 0..n {
+    assert!(set.insert(i));
+  }
+
+  let deleters: Vec<_> = (0..4).map(|t| {
+    let set = set.clone_ref();
+    thread::spawn(move || {
+      for i in (t..n).step_by(4) {
+        assert!(set.delete(i));
+      }
+    })
+  }).collect();
+
+  for t in deleters {
+    t.join().unwrap();
+  }
+
+  assert_eq!(set.len(), 0);
+}
+
+#[test]
+fn set_deferred() {
+  let config = RluConfig { mode: RluMode::Deferred, ..RluConfig::default() };
+  let set = RluSet::with_config(config);
+
+  let n = rounds(200);
+  for i in 0..n {
+    assert!(set.insert(i * 2));
+  }
+  set.sync();
+
+  let workers: Vec<_> = (0..4).map(|t| {
+    let set = set.clone_ref();
+    thread::spawn(move || {
+      let mut rng = thread_rng();
+
+      for i in 0..rounds(500) {
+        // odd values belong to one worker each, so this worker knows their state
+        let odd = (rng.gen_range(0, 50) * 4 + t) * 2 + 1;
+        if i % 2 == 0 {
+          set.insert(odd);
+          assert!(set.contains(odd));
+        } else {
+          set.delete(odd);
+          assert!(!set.contains(odd));
+        }
+        assert!(set.contains(rng.gen_range(0, n) * 2));
+      }
+    })
+  }).collect();
+
+  for t in workers {
+    t.join().unwrap();
+  }
+
+  for i in 0..n {
+    assert!(set.contains(i * 2));
+  }
+}
+
+#[test]
+fn set_deferred_idle_handle() {
+  let config = RluConfig { mode: RluMode::Deferred, ..RluConfig::default() };
+  let set = RluSet::with_config(config);
+  let other = set.clone_ref();
+
+  // the head stays locked by set until another handle needs it
+  assert!(set.insert(1));
+  assert!(other.insert(2));
+  assert!(other.delete(1));
+  other.sync();
+  assert!(!set.contains(1));
+  assert!(set.contains(2));
+}
+
+#[test]
+fn set_deferred_auto_register() {
+  let config = RluConfig { mode: RluMode::Deferred, auto_register: true, ..RluConfig::default() };
+  let set = Arc::new(RluSet::with_config(config));
+
+  // the worker writes back as it exits, this thread still holds its inserts when
+  // the set goes away
+  let worker = {
+    let set = set.clone();
+    thread::spawn(move || assert!(set.insert(1)))
+  };
+  worker.join().unwrap();
+  for i in 2..10 {
+    assert!(set.insert(i));
+  }
+  assert!(set.contains(1));
+  drop(set);
+}
+
+#[test]
+fn set_background_reclaimer() {
+  let set = RluSet::new();
+  set.start_reclaimer();
+
+  let workers: Vec<_> = (0..4).map(|t| {
+    let set = set.clone_ref();
+    thread::spawn(move || {
+      for round in 0..rounds(20) {
+        for i in (t..rounds(400)).step_by(4) {
+          assert!(set.insert(i));
+        }
+        for i in (t..rounds(400)).step_by(4) {
+          assert!(set.delete(i), "round {}: {} missing", round, i);
+        }
+      }
+    })
+  }).collect();
+
+  for t in workers {
+    t.join().unwrap();
+  }
+
+  set.stop_reclaimer();
+  assert_eq!(set.len(), 0);
+}